@@ -226,20 +226,46 @@ impl PointTrait for DVec2 {
     const DIMENSION: u8 = 2;
 }
 
-impl<P: PointTrait> Default for KDTree<P> {
+impl<P: PointTrait, V> Default for KDMap<P, V> {
     fn default() -> Self {
         Self {
+            nodes: Vec::new(),
             root: None,
+            slots: Vec::new(),
+            free_ids: Vec::new(),
             rect: None,
+            alpha: None,
+            rebuilds: 0,
+            max_len: 0,
+        }
+    }
+}
+
+impl<P: PointTrait> Default for KDTree<P> {
+    fn default() -> Self {
+        Self {
+            map: KDMap::default(),
+        }
+    }
+}
+
+impl<P: PointTrait, V> Debug for KDMap<P, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.root.is_some() {
+            writeln!(f, "KDMap(")?;
+            self.format_nodes(f)?;
+            writeln!(f, ")")
+        } else {
+            writeln!(f, "KDMap()")
         }
     }
 }
 
 impl<P: PointTrait> Debug for KDTree<P> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if let Some(ref root_node) = self.root {
+        if self.map.root.is_some() {
             writeln!(f, "KDTree(")?;
-            root_node.format_node(f, 0)?;
+            self.map.format_nodes(f)?;
             writeln!(f, ")")
         } else {
             writeln!(f, "KDTree()")
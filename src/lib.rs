@@ -28,19 +28,23 @@
 use approx::UlpsEq;
 use std::fmt::Display;
 use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
     fmt,
     fmt::Debug,
-    ops::{AddAssign, DivAssign, MulAssign, SubAssign},
+    ops::{AddAssign, ControlFlow, DivAssign, MulAssign, SubAssign},
 };
 #[cfg(feature = "vector-traits")]
 use vector_traits::approx::UlpsEq;
 
 #[cfg(not(feature = "vector-traits"))]
-use num_traits::{real::Real, FromPrimitive, Zero};
+use num_traits::{real::Real, FromPrimitive, One, Zero};
 #[cfg(feature = "vector-traits")]
-use vector_traits::num_traits::{real::Real, FromPrimitive, Zero};
+use vector_traits::num_traits::{real::Real, FromPrimitive, One, Zero};
 
 mod impls;
+pub mod region;
+pub use region::Region;
 
 #[cfg(test)]
 mod tests;
@@ -49,6 +53,8 @@ mod tests;
 pub enum KrakelError {
     #[error("Unknown error: {0}")]
     InternalError(String),
+    #[error("Invalid parameter: {0}")]
+    InvalidParameter(String),
 }
 
 pub trait PointTrait: Clone + PartialEq
@@ -88,297 +94,2420 @@ pub trait KDPoint<P: PointTrait> {
     fn set_coordinate(&mut self, index: usize, value: P::PScalar);
 }
 
-pub struct KDNode<P: PointTrait> {
+/// Subsets smaller than this are partitioned sequentially by `KDMap::par_partition()`.
+#[cfg(feature = "rayon")]
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+pub struct KDNode<P: PointTrait, V> {
     pos: P,
+    value: V,
+    /// The `PointId` of the entry currently stored in this node
+    id: usize,
     dir: u8,
-    left: Option<Box<KDNode<P>>>,
-    right: Option<Box<KDNode<P>>>,
+    /// The number of nodes in the subtree rooted at this node, including itself
+    size: usize,
+    /// Index of the left child in `KDTree::nodes`
+    left: Option<usize>,
+    /// Index of the right child in `KDTree::nodes`
+    right: Option<usize>,
 }
 
-#[derive(Clone)]
-struct HyperRectangle<P: PointTrait> {
-    min: P,
-    max: P,
+/// An axis aligned box, edges included. Used for the cells of the tree and as a [`Region`].
+#[derive(Clone, Debug)]
+pub struct HyperRectangle<P: PointTrait> {
+    pub min: P,
+    pub max: P,
 }
 
-pub struct KDTree<P: PointTrait> {
-    root: Option<Box<KDNode<P>>>,
+/// A kd-tree associating a value of type `V` with every point.
+///
+/// All the nodes of the tree are stored in `nodes` and refer to each other by index, so that
+/// neither the traversals nor dropping the tree need to recurse once per tree level.
+pub struct KDMap<P: PointTrait, V> {
+    nodes: Vec<KDNode<P, V>>,
+    root: Option<usize>,
+    /// Maps every `PointId` to the index of the node currently holding that entry
+    slots: Vec<Option<usize>>,
+    /// `PointId`s of removed entries, available for reuse
+    free_ids: Vec<usize>,
     rect: Option<HyperRectangle<P>>,
+    /// The α of the scapegoat balancing, `None` if balancing is disabled
+    alpha: Option<f64>,
+    rebuilds: usize,
+    /// The largest number of points held since the whole tree was last rebuilt
+    max_len: usize,
 }
 
-impl<P: PointTrait> KDNode<P> {
-    fn recursive_insert(
-        node: &mut Option<Box<KDNode<P>>>,
-        pos: P,
-        dir: u8,
-        dim: u8,
-    ) -> Result<(), KrakelError> {
-        match node {
-            None => {
-                *node = Some(Box::new(KDNode {
-                    pos,
-                    dir,
-                    left: None,
-                    right: None,
-                }));
-            }
-            Some(current) => {
-                let new_dir = (current.dir + 1) % dim;
-                if pos.at(current.dir) < current.pos.at(current.dir) {
-                    Self::recursive_insert(&mut current.left, pos, new_dir, dim)?;
-                } else {
-                    Self::recursive_insert(&mut current.right, pos, new_dir, dim)?;
-                }
+/// A handle to an entry of a [`KDMap`] or a [`KDTree`], as returned by `insert()`.
+///
+/// A handle stays valid until its entry is removed, no matter what other points are inserted,
+/// moved or removed in the meantime. Once an entry is removed its handle may be handed out again
+/// by a later insert.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PointId(usize);
+
+impl PointId {
+    /// Returns the handle as an index, handles are allocated densely starting at zero.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// A kd-tree of points, this is a [`KDMap`] without any values.
+pub struct KDTree<P: PointTrait> {
+    map: KDMap<P, ()>,
+}
+
+/// Statistics about the shape of a [`KDMap`] or [`KDTree`], see [`KDMap::stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KDTreeStats {
+    /// The number of points in the tree
+    pub len: usize,
+    /// The number of nodes on the longest path from the root to a leaf
+    pub depth: usize,
+    /// The number of subtrees rebuilt by the balancing, and of whole tree rebuilds after heavy
+    /// removal or [`KDMap::retain`], since the tree was created
+    pub rebuilds: usize,
+}
+
+/// Statistics about a single query, see [`KDMap::nearest_with_stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    /// The number of nodes the query descended into
+    pub visited: usize,
+}
+
+/// An entry of the explicit stack used by the depth first queries
+enum Step<S> {
+    /// Continue down into the nearer subtree of the node
+    Descend(usize),
+    /// The nearer subtree of the node is done, process the node itself and its farther subtree
+    Backtrack(usize),
+    /// A subtree is done, widen the cell back to what it was before entering it
+    Restore { dir: u8, max: bool, value: S },
+}
+
+/// A node index ordered by its squared distance, for use in a `BinaryHeap`
+struct Candidate<S> {
+    dist_sq: S,
+    index: usize,
+}
+
+impl<S: PartialOrd> PartialEq for Candidate<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<S: PartialOrd> Eq for Candidate<S> {}
+
+impl<S: PartialOrd> PartialOrd for Candidate<S> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<S: PartialOrd> Ord for Candidate<S> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.dist_sq
+            .partial_cmp(&other.dist_sq)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// An entry of the queue of [`NearestIter`]: either a node whose point is yet to be reported,
+/// or a whole subtree together with its cell. Ordered so that the closest entry is on top of a
+/// `BinaryHeap`.
+struct Pending<P: PointTrait> {
+    dist_sq: P::PScalar,
+    index: usize,
+    /// The cell of the subtree rooted at `index`, `None` if only the point of the node is pending
+    cell: Option<HyperRectangle<P>>,
+}
+
+impl<P: PointTrait> PartialEq for Pending<P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<P: PointTrait> Eq for Pending<P> {}
+
+impl<P: PointTrait> PartialOrd for Pending<P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<P: PointTrait> Ord for Pending<P> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .dist_sq
+            .partial_cmp(&self.dist_sq)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// An iterator over the entries of a [`KDMap`] in increasing distance from a point, see
+/// [`KDMap::nearest_iter`].
+///
+/// Subtrees and points are kept in a single priority queue ordered by distance, the point
+/// of a node or the subtrees of its children are only queued once the node itself reaches the
+/// front of the queue (Hjaltason & Samet). A point is reported when no pending subtree can
+/// hold anything closer.
+pub struct NearestIter<'a, P: PointTrait, V, Q> {
+    map: &'a KDMap<P, V>,
+    pos: &'a Q,
+    queue: BinaryHeap<Pending<P>>,
+}
+
+impl<'a, P: PointTrait, V, Q: PointTrait<PScalar = P::PScalar>> Iterator
+    for NearestIter<'a, P, V, Q>
+{
+    type Item = (P::PScalar, &'a P, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(pending) = self.queue.pop() {
+            let Some(mut cell) = pending.cell else {
+                let (pos, value) = self.map.entry(pending.index);
+                return Some((pending.dist_sq, pos, value));
+            };
+            let node = &self.map.nodes[pending.index];
+            self.queue.push(Pending {
+                dist_sq: PointTrait::dist_sq(&node.pos, self.pos),
+                index: pending.index,
+                cell: None,
+            });
+            let split = node.pos.at(node.dir);
+            if let Some(left) = node.left {
+                let mut left_cell = cell.clone();
+                *left_cell.max.at_mut(node.dir) = split;
+                self.push_subtree(left, left_cell);
+            }
+            if let Some(right) = node.right {
+                *cell.min.at_mut(node.dir) = split;
+                self.push_subtree(right, cell);
             }
         }
-        Ok(())
+        None
     }
+}
 
-    fn recursive_nearest<'a>(
-        &'a self,
-        pos: &P,
-        result: &mut Option<&'a P>,
-        result_dist_sq: &mut P::PScalar,
-        rect: &mut HyperRectangle<P>,
-    ) {
-        let dir = self.dir;
-
-        let (nearer_subtree, farther_subtree) = if pos.at(dir) <= self.pos.at(dir) {
-            (&self.left, &self.right)
-        } else {
-            (&self.right, &self.left)
-        };
+impl<P: PointTrait, V, Q: PointTrait<PScalar = P::PScalar>> NearestIter<'_, P, V, Q> {
+    fn push_subtree(&mut self, index: usize, cell: HyperRectangle<P>) {
+        self.queue.push(Pending {
+            dist_sq: KDMap::<P, V>::hyper_rect_dist_sq(&cell, self.pos),
+            index,
+            cell: Some(cell),
+        });
+    }
+}
 
-        let old_value = if pos.at(dir) <= self.pos.at(dir) {
-            std::mem::replace(&mut rect.max.at(dir), self.pos.at(dir))
-        } else {
-            std::mem::replace(&mut rect.min.at(dir), self.pos.at(dir))
+/// A depth first walk over the nodes of a tree, visiting the subtree nearer to `pos` first (or
+/// the farther one first, for the farthest point queries).
+///
+/// The walk keeps track of the cell of the current node, i.e. the part of space its subtree
+/// can hold points in. The cell is narrowed by the split of every node it enters and widened
+/// again when the subtree is done, so that farther subtrees can be pruned by their own cell
+/// rather than by the bounds of the whole tree.
+///
+/// For distance based pruning the nearer subtree never needs to be checked, as its cell is as
+/// near to `pos` as the cell of its parent. Walks for other predicates, such as regions, check
+/// the cells of both subtrees (see [`CellWalk::checked`]).
+struct CellWalk<'a, P: PointTrait, V, Q> {
+    nodes: &'a [KDNode<P, V>],
+    pos: &'a Q,
+    stack: Vec<Step<P::PScalar>>,
+    cell: HyperRectangle<P>,
+    /// The node returned last, its second subtree is yet to be considered
+    pending: Option<usize>,
+    /// The nodes from the root down to the one entered last
+    path: Vec<usize>,
+    visited: usize,
+    farthest_first: bool,
+    /// `true` if the first subtree of every node is checked too, not just the second one
+    check_first: bool,
+}
+
+impl<P: PointTrait> HyperRectangle<P> {
+    /// Returns the bounding rectangle of `points`, or `None` if `points` is empty.
+    fn from_points<'a>(mut points: impl Iterator<Item = &'a P>) -> Option<Self>
+    where
+        P: 'a,
+    {
+        let first = points.next()?;
+        let mut rect = HyperRectangle {
+            min: first.clone(),
+            max: first.clone(),
         };
+        for pos in points {
+            rect.expand(pos);
+        }
+        Some(rect)
+    }
+
+    /// Creates the box spanned by `min` and `max`, `min` must not be greater than `max` along
+    /// any axis.
+    pub fn new(min: P, max: P) -> Self {
+        Self { min, max }
+    }
+
+    /// Returns `true` if the two rectangles overlap, touching edges included.
+    pub fn intersects(&self, other: &Self) -> bool {
+        (0..P::DIMENSION)
+            .all(|i| self.min.at(i) <= other.max.at(i) && other.min.at(i) <= self.max.at(i))
+    }
 
-        if let Some(nearer_node) = nearer_subtree {
-            nearer_node.recursive_nearest(pos, result, result_dist_sq, rect);
+    /// Returns `true` if `pos` is inside the rectangle or on its edge.
+    pub fn contains(&self, pos: &P) -> bool {
+        (0..P::DIMENSION).all(|i| self.min.at(i) <= pos.at(i) && pos.at(i) <= self.max.at(i))
+    }
+
+    /// Grows the rectangle so that it contains `pos`.
+    fn expand(&mut self, pos: &P) {
+        for i in 0..P::DIMENSION {
+            if pos.at(i) < self.min.at(i) {
+                *self.min.at_mut(i) = pos.at(i);
+            } else if pos.at(i) > self.max.at(i) {
+                *self.max.at_mut(i) = pos.at(i);
+            }
         }
+    }
+}
+
+impl<'a, P: PointTrait, V, Q: PointTrait<PScalar = P::PScalar>> CellWalk<'a, P, V, Q> {
+    /// Starts a walk from the root of `map`, or returns `None` if `map` is empty.
+    fn new(map: &'a KDMap<P, V>, pos: &'a Q) -> Option<Self> {
+        Some(Self {
+            nodes: &map.nodes,
+            pos,
+            stack: vec![Step::Descend(map.root?)],
+            cell: map.rect.clone()?,
+            pending: None,
+            path: Vec::new(),
+            visited: 0,
+            farthest_first: false,
+            check_first: false,
+        })
+    }
 
-        if pos.at(dir) <= self.pos.at(dir) {
-            *rect.max.at_mut(dir) = old_value;
+    /// Starts a walk from the root of `map` that checks the cells of both subtrees of every
+    /// node before entering them. The subtree nearer to `pos` is still walked first.
+    fn checked(map: &'a KDMap<P, V>, pos: &'a Q) -> Option<Self> {
+        let mut walk = Self::new(map, pos)?;
+        walk.check_first = true;
+        Some(walk)
+    }
+
+    /// Starts a walk from the root of `map` that visits the subtree holding the corner of the
+    /// cell farthest from `pos` first.
+    fn farthest_first(map: &'a KDMap<P, V>, pos: &'a Q) -> Option<Self> {
+        let mut walk = Self::new(map, pos)?;
+        walk.farthest_first = true;
+        Some(walk)
+    }
+
+    /// Returns the (first, second) children of `node` in the order of the walk, `node` must be
+    /// the node whose cell is the current one.
+    #[inline(always)]
+    fn children(&self, node: &KDNode<P, V>) -> (Option<usize>, Option<usize>) {
+        if self.farthest_first {
+            let pos = self.pos.at(node.dir);
+            if pos - self.cell.min.at(node.dir) > self.cell.max.at(node.dir) - pos {
+                (node.left, node.right)
+            } else {
+                (node.right, node.left)
+            }
         } else {
-            *rect.min.at_mut(dir) = old_value;
+            node.children_towards(self.pos)
         }
+    }
 
-        let dist_sq = PointTrait::dist_sq(&self.pos, pos);
-        if dist_sq < *result_dist_sq {
-            *result_dist_sq = dist_sq;
-            *result = Some(&self.pos);
+    /// Returns the next node of the walk, nodes are returned once their first subtree is done.
+    ///
+    /// The second subtree of the node returned by the previous call is only walked if `enter`
+    /// returns `true` for its cell. For a [`CellWalk::checked`] walk the same goes for the
+    /// first subtree of every node entered during this call.
+    fn next<F>(&mut self, mut enter: F) -> Option<usize>
+    where
+        F: FnMut(&HyperRectangle<P>) -> bool,
+    {
+        if let Some(index) = self.pending.take() {
+            let node = &self.nodes[index];
+            if let (_, Some(second)) = self.children(node) {
+                let max = node.left == Some(second);
+                let value = self.replace_bound(node.dir, max, node.pos.at(node.dir));
+                if enter(&self.cell) {
+                    self.stack.push(Step::Restore {
+                        dir: node.dir,
+                        max,
+                        value,
+                    });
+                    self.stack.push(Step::Descend(second));
+                } else {
+                    let _ = self.replace_bound(node.dir, max, value);
+                }
+            }
         }
-
-        if let Some(farther_node) = farther_subtree {
-            if KDTree::hyper_rect_dist_sq(rect, pos) < *result_dist_sq {
-                farther_node.recursive_nearest(pos, result, result_dist_sq, rect);
+        while let Some(step) = self.stack.pop() {
+            match step {
+                Step::Descend(index) => {
+                    self.visited += 1;
+                    self.path.push(index);
+                    self.stack.push(Step::Backtrack(index));
+                    let node = &self.nodes[index];
+                    if let (Some(first), _) = self.children(node) {
+                        // with distance pruning the first cell is as near to (or as far from)
+                        // pos as the cell of the node itself, so it only needs to be checked
+                        // for other predicates
+                        let max = node.left == Some(first);
+                        let value = self.replace_bound(node.dir, max, node.pos.at(node.dir));
+                        if self.check_first && !enter(&self.cell) {
+                            let _ = self.replace_bound(node.dir, max, value);
+                        } else {
+                            self.stack.push(Step::Restore {
+                                dir: node.dir,
+                                max,
+                                value,
+                            });
+                            self.stack.push(Step::Descend(first));
+                        }
+                    }
+                }
+                Step::Backtrack(index) => {
+                    self.pending = Some(index);
+                    return Some(index);
+                }
+                Step::Restore { dir, max, value } => {
+                    // the subtree is done
+                    let _ = self.path.pop();
+                    let _ = self.replace_bound(dir, max, value);
+                }
             }
         }
+        None
     }
 
-    fn recursive_range_query<Q: PointTrait<PScalar = P::PScalar>>(
-        &self,
-        pos: &Q,
-        radius_sq: P::PScalar,
-        results: &mut Vec<P>,
-        rect: &mut HyperRectangle<P>,
-    ) {
-        let dir = self.dir;
-
-        let (nearer_subtree, farther_subtree) = if pos.at(dir) <= self.pos.at(dir) {
-            (&self.left, &self.right)
+    /// Sets the upper (`max`) or lower bound of the cell along `dir`, and returns the old bound.
+    #[inline(always)]
+    fn replace_bound(&mut self, dir: u8, max: bool, value: P::PScalar) -> P::PScalar {
+        let bound = if max {
+            self.cell.max.at_mut(dir)
         } else {
-            (&self.right, &self.left)
+            self.cell.min.at_mut(dir)
         };
+        std::mem::replace(bound, value)
+    }
 
-        let old_value = if pos.at(dir) <= self.pos.at(dir) {
-            std::mem::replace(&mut rect.max.at(dir), self.pos.at(dir))
-        } else {
-            std::mem::replace(&mut rect.min.at(dir), self.pos.at(dir))
-        };
+    /// Returns the nodes from the root down to the node returned last.
+    fn path(&self) -> &[usize] {
+        &self.path
+    }
+
+    fn stats(&self) -> QueryStats {
+        QueryStats {
+            visited: self.visited,
+        }
+    }
+}
 
-        if let Some(nearer_node) = nearer_subtree {
-            nearer_node.recursive_range_query(pos, radius_sq, results, rect);
+impl<P: PointTrait, V> KDNode<P, V> {
+    fn new(pos: P, value: V, id: usize) -> Self {
+        KDNode {
+            pos,
+            value,
+            id,
+            dir: 0,
+            size: 1,
+            left: None,
+            right: None,
         }
+    }
 
-        if pos.at(dir) <= self.pos.at(dir) {
-            *rect.max.at_mut(dir) = old_value;
+    /// Returns the (nearer, farther) children of this node as seen from `pos`
+    #[inline(always)]
+    fn children_towards<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+    ) -> (Option<usize>, Option<usize>) {
+        if pos.at(self.dir) <= self.pos.at(self.dir) {
+            (self.left, self.right)
         } else {
-            *rect.min.at_mut(dir) = old_value;
+            (self.right, self.left)
         }
+    }
+}
+
+impl<P: PointTrait, V> KDMap<P, V> {
+    /// Builds a balanced tree from `entries` in O(n log n).
+    ///
+    /// The resulting shape does not depend on the order of `entries`, so this is the preferred
+    /// way of populating a tree when all the points are known up front.
+    /// The n:th entry is given the `PointId` with index n.
+    pub fn from_entries(entries: Vec<(P, V)>) -> Self {
+        let mut nodes = Self::new_nodes(entries);
+        Self::partition(&mut nodes, 0, P::DIMENSION, &|node: &KDNode<P, V>, dir| {
+            node.pos.at(dir)
+        });
+        Self::from_partitioned(nodes)
+    }
 
-        let dist_sq = PointTrait::dist_sq(&self.pos, pos);
-        if dist_sq <= radius_sq {
-            results.push(self.pos.clone());
+    /// Creates an empty tree that keeps itself balanced while points are inserted one at a time.
+    ///
+    /// Whenever an insert would place a node deeper than log<sub>1/α</sub>(n), the lowest subtree
+    /// on its path where one side holds more than `alpha` of the nodes is rebuilt (scapegoat
+    /// style). `alpha` must be in the range (0.5, 1.0): lower values keep the tree closer to
+    /// perfectly balanced at the price of more frequent rebuilds.
+    pub fn with_balancing(alpha: f64) -> Result<Self, KrakelError> {
+        if !(alpha > 0.5 && alpha < 1.0) {
+            return Err(KrakelError::InvalidParameter(format!(
+                "alpha must be in the range (0.5, 1.0), got {}",
+                alpha
+            )));
         }
+        Ok(Self {
+            alpha: Some(alpha),
+            ..Default::default()
+        })
+    }
 
-        if let Some(farther_node) = farther_subtree {
-            if KDTree::hyper_rect_dist_sq(rect, pos) <= radius_sq {
-                farther_node.recursive_range_query(pos, radius_sq, results, rect);
-            }
+    /// Builds the same balanced tree as [`KDMap::from_entries`], but splits the work over the
+    /// rayon thread pool.
+    #[cfg(feature = "rayon")]
+    pub fn par_from_entries(entries: Vec<(P, V)>) -> Self
+    where
+        P: Send,
+        V: Send,
+    {
+        let mut nodes = Self::new_nodes(entries);
+        Self::par_partition(&mut nodes, 0, P::DIMENSION, &|node: &KDNode<P, V>, dir| {
+            node.pos.at(dir)
+        });
+        Self::from_partitioned(nodes)
+    }
+
+    /// Creates unlinked nodes, using the index of every entry as its id
+    fn new_nodes(entries: Vec<(P, V)>) -> Vec<KDNode<P, V>> {
+        entries
+            .into_iter()
+            .enumerate()
+            .map(|(id, (pos, value))| KDNode::new(pos, value, id))
+            .collect()
+    }
+
+    /// Creates the tree from nodes already ordered by `partition()`
+    fn from_partitioned(mut nodes: Vec<KDNode<P, V>>) -> Self {
+        let rect = HyperRectangle::from_points(nodes.iter().map(|node| &node.pos));
+        let mut slots = vec![None; nodes.len()];
+        for (index, node) in nodes.iter().enumerate() {
+            slots[node.id] = Some(index);
+        }
+        let order: Vec<usize> = (0..nodes.len()).collect();
+        let root = Self::link(&mut nodes, &order, 0, P::DIMENSION);
+        Self {
+            max_len: nodes.len(),
+            nodes,
+            root,
+            slots,
+            rect,
+            ..Default::default()
         }
     }
 
-    fn recursive_closure_range_query<Q: PointTrait<PScalar = P::PScalar>, F>(
-        &self,
-        pos: &Q,
-        radius_sq: P::PScalar,
-        rect: &mut HyperRectangle<P>,
-        process: &mut F,
-    ) where
-        F: FnMut(&P),
+    /// Reorders `items` so that the median along `dir` ends up in the middle, with the items
+    /// below it to the left and the items above it to the right. Both halves are then
+    /// partitioned the same way along the next direction.
+    /// Items equal to the median along `dir` may end up on either side of it.
+    fn partition<T, K>(items: &mut [T], dir: u8, dim: u8, key: &K)
+    where
+        K: Fn(&T, u8) -> P::PScalar,
+    {
+        if items.len() <= 1 {
+            return;
+        }
+        let mid = items.len() / 2;
+        items.select_nth_unstable_by(mid, |a, b| {
+            key(a, dir)
+                .partial_cmp(&key(b, dir))
+                .unwrap_or(Ordering::Equal)
+        });
+        let (left, right) = items.split_at_mut(mid);
+        let new_dir = (dir + 1) % dim;
+        Self::partition(left, new_dir, dim, key);
+        Self::partition(&mut right[1..], new_dir, dim, key);
+    }
+
+    /// Same as `partition()`, but the two halves of every split larger than
+    /// `PARALLEL_BUILD_THRESHOLD` are partitioned concurrently.
+    #[cfg(feature = "rayon")]
+    fn par_partition<T, K>(items: &mut [T], dir: u8, dim: u8, key: &K)
+    where
+        T: Send,
+        K: Fn(&T, u8) -> P::PScalar + Sync,
     {
-        let dir = self.dir;
+        if items.len() < PARALLEL_BUILD_THRESHOLD {
+            return Self::partition(items, dir, dim, key);
+        }
+        let mid = items.len() / 2;
+        items.select_nth_unstable_by(mid, |a, b| {
+            key(a, dir)
+                .partial_cmp(&key(b, dir))
+                .unwrap_or(Ordering::Equal)
+        });
+        let (left, right) = items.split_at_mut(mid);
+        let new_dir = (dir + 1) % dim;
+        let _ = rayon::join(
+            || Self::par_partition(left, new_dir, dim, key),
+            || Self::par_partition(&mut right[1..], new_dir, dim, key),
+        );
+    }
 
-        let (nearer_subtree, farther_subtree) = if pos.at(dir) <= self.pos.at(dir) {
-            (&self.left, &self.right)
-        } else {
-            (&self.right, &self.left)
-        };
+    /// Links the nodes listed in `order`, as ordered by `partition()`, into a balanced subtree
+    /// and returns the index of its root.
+    fn link(nodes: &mut [KDNode<P, V>], order: &[usize], dir: u8, dim: u8) -> Option<usize> {
+        if order.is_empty() {
+            return None;
+        }
+        let mid = order.len() / 2;
+        let new_dir = (dir + 1) % dim;
+        let left = Self::link(nodes, &order[..mid], new_dir, dim);
+        let right = Self::link(nodes, &order[mid + 1..], new_dir, dim);
+        let node = &mut nodes[order[mid]];
+        node.dir = dir;
+        node.size = order.len();
+        node.left = left;
+        node.right = right;
+        Some(order[mid])
+    }
+
+    /// Inserts `pos` with its `value` and returns the handle of the new entry.
+    pub fn insert(&mut self, pos: P, value: V) -> Result<PointId, KrakelError> {
+        let id = self.free_ids.pop().unwrap_or_else(|| {
+            self.slots.push(None);
+            self.slots.len() - 1
+        });
+        self.insert_node(KDNode::new(pos, value, id));
+        Ok(PointId(id))
+    }
 
-        let old_value = if pos.at(dir) <= self.pos.at(dir) {
-            std::mem::replace(&mut rect.max.at(dir), self.pos.at(dir))
+    /// Links `node` into the tree as a new leaf. The links and the size of `node` are reset,
+    /// only its entry is kept.
+    fn insert_node(&mut self, mut node: KDNode<P, V>) {
+        let pos = &node.pos;
+        if let Some(rect) = self.rect.as_mut() {
+            rect.expand(pos);
         } else {
-            std::mem::replace(&mut rect.min.at(dir), self.pos.at(dir))
-        };
+            self.rect = Some(HyperRectangle {
+                min: pos.clone(),
+                max: pos.clone(),
+            });
+        }
 
-        if let Some(nearer_node) = nearer_subtree {
-            nearer_node.recursive_closure_range_query(pos, radius_sq, rect, process);
+        let mut path = Vec::new();
+        let mut current = self.root;
+        while let Some(index) = current {
+            path.push(index);
+            let node = &mut self.nodes[index];
+            node.size += 1;
+            current = if pos.at(node.dir) < node.pos.at(node.dir) {
+                node.left
+            } else {
+                node.right
+            };
         }
 
-        if pos.at(dir) <= self.pos.at(dir) {
-            *rect.max.at_mut(dir) = old_value;
+        let index = self.nodes.len();
+        self.slots[node.id] = Some(index);
+        node.dir = 0;
+        node.size = 1;
+        node.left = None;
+        node.right = None;
+        if let Some(&parent) = path.last() {
+            let parent = &mut self.nodes[parent];
+            node.dir = (parent.dir + 1) % P::DIMENSION;
+            if node.pos.at(parent.dir) < parent.pos.at(parent.dir) {
+                parent.left = Some(index);
+            } else {
+                parent.right = Some(index);
+            }
         } else {
-            *rect.min.at_mut(dir) = old_value;
+            self.root = Some(index);
         }
+        self.nodes.push(node);
+        self.max_len = self.max_len.max(self.nodes.len());
 
-        if PointTrait::dist_sq(&self.pos, pos) <= radius_sq {
-            process(&self.pos);
+        if let Some(alpha) = self.alpha {
+            let max_depth = (self.nodes.len() as f64).log(1.0 / alpha) as usize;
+            if path.len() > max_depth {
+                self.rebuild_scapegoat(&path, alpha);
+            }
         }
+    }
 
-        if let Some(farther_node) = farther_subtree {
-            if KDTree::hyper_rect_dist_sq(rect, pos) <= radius_sq {
-                farther_node.recursive_closure_range_query(pos, radius_sq, rect, process);
+    /// Rebuilds the lowest node on `path` whose child on the path holds more than `alpha` of its
+    /// nodes. `path` lists the ancestors of the most recently inserted node.
+    fn rebuild_scapegoat(&mut self, path: &[usize], alpha: f64) {
+        let mut child_size = 1;
+        for (depth, &index) in path.iter().enumerate().rev() {
+            let size = self.nodes[index].size;
+            if child_size as f64 > alpha * size as f64 {
+                let parent = depth.checked_sub(1).map(|depth| path[depth]);
+                self.rebuild(parent, index);
+                self.rebuilds += 1;
+                return;
             }
+            child_size = size;
         }
     }
 
-    fn format_node(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
-        for _ in 0..depth {
-            write!(f, " ")?;
+    /// Replaces the subtree rooted at `index` with a balanced one containing the same nodes.
+    fn rebuild(&mut self, parent: Option<usize>, index: usize) {
+        let dir = self.nodes[index].dir;
+        let mut order = self.subtree(index);
+        let nodes = &self.nodes;
+        Self::partition(&mut order, dir, P::DIMENSION, &|&i: &usize, dir| {
+            nodes[i].pos.at(dir)
+        });
+        let root = Self::link(&mut self.nodes, &order, dir, P::DIMENSION);
+        *self.link_to(parent, index) = root;
+    }
+
+    /// Rebuilds the whole tree into a balanced one once removals have left it with fewer than α
+    /// (or half, without balancing) of the most points it held since its last full rebuild.
+    fn rebuild_if_shrunk(&mut self) {
+        let fraction = self.alpha.unwrap_or(0.5);
+        if (self.nodes.len() as f64) < fraction * self.max_len as f64 {
+            self.rebuild_all();
         }
+    }
 
-        write!(f, "d={} node at ", self.dir)?;
-        for i in 0..P::DIMENSION {
-            write!(f, "{} ", self.pos.at(i))?;
+    /// Links all the nodes into a balanced tree, regardless of their current links, and shrinks
+    /// the bounding rectangle to fit them.
+    fn rebuild_all(&mut self) {
+        let mut order: Vec<usize> = (0..self.nodes.len()).collect();
+        let nodes = &self.nodes;
+        Self::partition(&mut order, 0, P::DIMENSION, &|&i: &usize, dir| {
+            nodes[i].pos.at(dir)
+        });
+        self.root = Self::link(&mut self.nodes, &order, 0, P::DIMENSION);
+        self.rect = HyperRectangle::from_points(self.nodes.iter().map(|node| &node.pos));
+        self.max_len = self.nodes.len();
+        self.rebuilds += 1;
+    }
+
+    /// Returns the indices of all the nodes in the subtree rooted at `index`.
+    fn subtree(&self, index: usize) -> Vec<usize> {
+        let mut result = Vec::with_capacity(self.nodes[index].size);
+        let mut stack = vec![index];
+        while let Some(index) = stack.pop() {
+            result.push(index);
+            let node = &self.nodes[index];
+            stack.extend(node.left);
+            stack.extend(node.right);
         }
-        writeln!(f)?;
+        result
+    }
 
-        if let Some(ref left_node) = self.left {
-            left_node.format_node(f, depth + 1)?;
+    /// Returns the link in `parent` (or the root link) currently pointing at `child`.
+    fn link_to(&mut self, parent: Option<usize>, child: usize) -> &mut Option<usize> {
+        match parent {
+            None => &mut self.root,
+            Some(parent) => {
+                let parent = &mut self.nodes[parent];
+                if parent.left == Some(child) {
+                    &mut parent.left
+                } else {
+                    &mut parent.right
+                }
+            }
         }
+    }
 
-        if let Some(ref right_node) = self.right {
-            right_node.format_node(f, depth + 1)?;
+    /// Returns the number of points in the tree.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns statistics about the shape of the tree. This walks the whole tree.
+    pub fn stats(&self) -> KDTreeStats {
+        KDTreeStats {
+            len: self.len(),
+            depth: self.depth(),
+            rebuilds: self.rebuilds,
         }
+    }
 
-        Ok(())
+    /// Returns the number of nodes on the longest path from the root to a leaf.
+    pub fn depth(&self) -> usize {
+        let mut result = 0;
+        let mut stack: Vec<(usize, usize)> = self.root.map(|root| (root, 1)).into_iter().collect();
+        while let Some((index, depth)) = stack.pop() {
+            result = result.max(depth);
+            let node = &self.nodes[index];
+            stack.extend(node.left.map(|child| (child, depth + 1)));
+            stack.extend(node.right.map(|child| (child, depth + 1)));
+        }
+        result
     }
-}
 
-impl<P: PointTrait> KDTree<P> {
-    pub fn insert(&mut self, pos: P) -> Result<(), KrakelError> {
-        KDNode::recursive_insert(&mut self.root, pos.clone(), 0, P::DIMENSION)?;
+    /// Removes one point equal to `pos` from the tree and returns it together with its value,
+    /// or `None` if no such point was found.
+    ///
+    /// The bounding rectangle of the tree is not shrunk, so it stays a conservative bound. Once
+    /// removals have left the tree with fewer than α (or half, without balancing) of the most
+    /// points it held, the whole tree is rebuilt balanced and its rectangle refitted.
+    pub fn remove(&mut self, pos: &P) -> Option<(P, V)> {
+        let mut path = Vec::new();
+        if !self.find_path(
+            self.root?,
+            pos,
+            |index| self.nodes[index].pos == *pos,
+            &mut path,
+        ) {
+            return None;
+        }
+        Some(self.remove_path(path))
+    }
 
-        if self.rect.is_none() {
-            self.rect = Some(HyperRectangle {
-                min: pos.clone(),
-                max: pos.clone(),
-            });
-        } else {
-            for i in 0..P::DIMENSION {
-                if pos.at(i) < self.rect.as_mut().unwrap().min.at(i) {
-                    *self.rect.as_mut().unwrap().min.at_mut(i) = pos.at(i);
-                } else if pos.at(i) > self.rect.as_mut().unwrap().max.at(i) {
-                    *self.rect.as_mut().unwrap().max.at_mut(i) = pos.at(i);
-                }
+    /// Removes and returns the point closest to `pos` and its value, or `None` if the tree is
+    /// empty.
+    ///
+    /// The path down to the closest point is recorded while searching for it, so it does not
+    /// have to be looked up again before the removal.
+    pub fn remove_nearest<Q: PointTrait<PScalar = P::PScalar>>(
+        &mut self,
+        pos: &Q,
+    ) -> Option<(P, V)> {
+        let mut walk = CellWalk::new(self, pos)?;
+        let mut nearest: Option<P::PScalar> = None;
+        let mut path = Vec::new();
+        while let Some(index) = walk.next(|cell| {
+            nearest.is_none_or(|nearest| Self::hyper_rect_dist_sq(cell, pos) < nearest)
+        }) {
+            let dist_sq = PointTrait::dist_sq(&self.nodes[index].pos, pos);
+            if nearest.is_none_or(|nearest| dist_sq < nearest) {
+                nearest = Some(dist_sq);
+                path.clear();
+                path.extend_from_slice(walk.path());
             }
         }
-        Ok(())
+        Some(self.remove_path(path))
     }
 
-    #[allow(dead_code)]
-    pub fn nearest(&self, pos: &P) -> Option<P> {
-        if let Some(root_node) = &self.root {
-            // Now that we know self.root is Some(_), it's safe to assume self.rect is Some(_) as well
-            let mut rect = self.rect.clone().unwrap();
-            let mut result: Option<&P> = self.root.as_ref().map(|node| &node.pos);
-            let mut result_dist_sq = P::dist_sq(result.as_ref().unwrap(), pos);
-
-            root_node.recursive_nearest(pos, &mut result, &mut result_dist_sq, &mut rect);
-            result.cloned()
-        } else {
-            None
-        }
+    /// Removes the entry with the handle `id` and returns its point and value, or `None` if
+    /// there is no such entry.
+    pub fn remove_by_id(&mut self, id: PointId) -> Option<(P, V)> {
+        let target = self.node_index(id)?;
+        Some(self.remove_node(target))
     }
 
-    #[allow(dead_code)]
-    pub fn range_query<Q: PointTrait<PScalar = P::PScalar>>(
-        &self,
+    /// Removes every point within `radius` of `pos` and returns them together with their values.
+    pub fn remove_within<Q: PointTrait<PScalar = P::PScalar>>(
+        &mut self,
         pos: &Q,
         radius: P::PScalar,
-    ) -> Vec<P> {
-        if let Some(root_node) = &self.root {
-            let mut results: Vec<P> = Vec::new();
-            let mut cloned_rect = self.rect.clone().unwrap();
+    ) -> Vec<(P, V)> {
+        self.range_query_ids(pos, radius)
+            .into_iter()
+            .map(|id| self.remove_by_id(id).unwrap())
+            .collect()
+    }
 
-            root_node.recursive_range_query(pos, radius * radius, &mut results, &mut cloned_rect);
-            results
-        } else {
-            Vec::new()
+    /// Keeps only the entries for which `keep` returns `true`. The remaining entries keep their
+    /// handles, and the tree is rebuilt into a balanced shape if anything was removed.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&P, &mut V) -> bool,
+    {
+        let len = self.nodes.len();
+        let mut kept = Vec::with_capacity(len);
+        for mut node in std::mem::take(&mut self.nodes) {
+            if keep(&node.pos, &mut node.value) {
+                self.slots[node.id] = Some(kept.len());
+                kept.push(node);
+            } else {
+                self.slots[node.id] = None;
+                self.free_ids.push(node.id);
+            }
+        }
+        // the surviving nodes kept their indices if nothing was removed, so the links are valid
+        let removed = kept.len() < len;
+        self.nodes = kept;
+        if removed {
+            self.rebuild_all();
         }
     }
 
-    pub fn closure_range_query<Q: PointTrait<PScalar = P::PScalar>, F>(
-        &self,
-        pos: &Q,
-        radius: P::PScalar,
-        mut process: F,
-    ) where
-        F: FnMut(&P),
+    /// Removes all the entries from the tree and returns them, in no particular order.
+    pub fn drain(&mut self) -> Vec<(P, V)> {
+        let nodes = std::mem::take(&mut self.nodes);
+        self.clear();
+        nodes
+            .into_iter()
+            .map(|node| (node.pos, node.value))
+            .collect()
+    }
+
+    /// Removes all the entries from the tree. The balancing settings are kept, and handles will
+    /// be handed out from zero again.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.root = None;
+        self.slots.clear();
+        self.free_ids.clear();
+        self.rect = None;
+        self.max_len = 0;
+    }
+
+    /// Returns the point and value of the entry with the handle `id`.
+    pub fn get(&self, id: PointId) -> Option<(&P, &V)> {
+        self.node_index(id).map(|index| self.entry(index))
+    }
+
+    #[inline(always)]
+    fn node_index(&self, id: PointId) -> Option<usize> {
+        self.slots.get(id.0).copied().flatten()
+    }
+
+    /// Removes the node at `target`, which must be linked into the tree.
+    fn remove_node(&mut self, target: usize) -> (P, V) {
+        let path = self.path_to(target);
+        self.remove_path(path)
+    }
+
+    /// Returns the indices from the root down to `target`, which must be linked into the tree.
+    fn path_to(&self, target: usize) -> Vec<usize> {
+        let mut path = Vec::new();
+        let _ = self.find_path(
+            self.root.unwrap(),
+            &self.nodes[target].pos,
+            |index| index == target,
+            &mut path,
+        );
+        path
+    }
+
+    /// Moves one point equal to `old` to `pos` and returns the handle of its entry, or `None`
+    /// if no such point was found.
+    ///
+    /// The node is updated in place if `pos` keeps it on the same side of every split plane
+    /// around it, otherwise the entry is removed and inserted again.
+    /// The bounding rectangle of the tree is only ever grown, so it stays a conservative bound.
+    pub fn update(&mut self, old: &P, pos: P) -> Option<PointId> {
+        let mut path = Vec::new();
+        if !self.find_path(
+            self.root?,
+            old,
+            |index| self.nodes[index].pos == *old,
+            &mut path,
+        ) {
+            return None;
+        }
+        let id = PointId(self.nodes[*path.last().unwrap()].id);
+        let _ = self.update_path(path, pos);
+        Some(id)
+    }
+
+    /// Moves the entry with the handle `id` to `pos` and returns its previous position, or
+    /// `None` if there is no such entry. See [`KDMap::update`].
+    pub fn update_by_id(&mut self, id: PointId, pos: P) -> Option<P> {
+        let target = self.node_index(id)?;
+        let path = self.path_to(target);
+        Some(self.update_path(path, pos))
+    }
+
+    /// Moves the last node of `path`, where `path` runs from the root down to that node, to
+    /// `pos` and returns its previous position.
+    fn update_path(&mut self, path: Vec<usize>, pos: P) -> P {
+        if self.fits_in_place(&path, &pos) {
+            // fits_in_place() never accepts an empty path, so the tree has a rect
+            self.rect.as_mut().unwrap().expand(&pos);
+            return std::mem::replace(&mut self.nodes[*path.last().unwrap()].pos, pos);
+        }
+        let mut node = self.unlink_path(path);
+        let old = std::mem::replace(&mut node.pos, pos);
+        self.insert_node(node);
+        old
+    }
+
+    /// Returns `true` if the last node of `path` can be moved to `pos` without breaking the
+    /// order of the tree: `pos` must stay on the same side of the splits of all its ancestors,
+    /// and the subtrees of the node must stay on their sides of its new split.
+    fn fits_in_place(&self, path: &[usize], pos: &P) -> bool {
+        let Some(&target) = path.last() else {
+            return false;
+        };
+        for pair in path.windows(2) {
+            let (parent, child) = (&self.nodes[pair[0]], pair[1]);
+            let (value, split) = (pos.at(parent.dir), parent.pos.at(parent.dir));
+            let same_side = if parent.left == Some(child) {
+                value <= split
+            } else {
+                value >= split
+            };
+            if !same_side {
+                return false;
+            }
+        }
+        let node = &self.nodes[target];
+        let (value, split) = (pos.at(node.dir), node.pos.at(node.dir));
+        match value.partial_cmp(&split) {
+            Some(Ordering::Equal) => true,
+            Some(Ordering::Less) => node.left.is_none_or(|left| {
+                let max = self.extreme_node(left, node.dir, Ordering::Greater);
+                self.nodes[max].pos.at(node.dir) <= value
+            }),
+            Some(Ordering::Greater) => node.right.is_none_or(|right| {
+                let min = self.extreme_node(right, node.dir, Ordering::Less);
+                self.nodes[min].pos.at(node.dir) >= value
+            }),
+            None => false,
+        }
+    }
+
+    /// Searches the subtree rooted at `start` for a node at the coordinates of `pos` that
+    /// satisfies `matches`. If found, the indices from `start` down to that node are appended to
+    /// `path`.
+    /// Points equal to a split value may be stored on either side of it, so both subtrees are
+    /// searched in that case.
+    fn find_path<F>(&self, start: usize, pos: &P, matches: F, path: &mut Vec<usize>) -> bool
+    where
+        F: Fn(usize) -> bool,
     {
-        if let Some(root_node) = &self.root {
-            let mut cloned_rect = self.rect.clone().unwrap();
+        let base = path.len();
+        let mut stack = vec![(start, base)];
+        while let Some((index, depth)) = stack.pop() {
+            path.truncate(depth);
+            path.push(index);
+            let node = &self.nodes[index];
+            let (value, split) = (pos.at(node.dir), node.pos.at(node.dir));
+            if value < split {
+                stack.extend(node.left.map(|child| (child, depth + 1)));
+            } else if value > split {
+                stack.extend(node.right.map(|child| (child, depth + 1)));
+            } else if matches(index) {
+                return true;
+            } else {
+                stack.extend(node.right.map(|child| (child, depth + 1)));
+                stack.extend(node.left.map(|child| (child, depth + 1)));
+            }
+        }
+        path.truncate(base);
+        false
+    }
+
+    /// Removes the last node of `path`, where `path` runs from the root down to that node, and
+    /// releases its handle.
+    fn remove_path(&mut self, path: Vec<usize>) -> (P, V) {
+        let removed = self.unlink_path(path);
+        self.slots[removed.id] = None;
+        self.free_ids.push(removed.id);
+        if self.root.is_none() {
+            self.rect = None;
+        } else {
+            self.rebuild_if_shrunk();
+        }
+        (removed.pos, removed.value)
+    }
+
+    /// Unlinks the last node of `path`, where `path` runs from the root down to that node, and
+    /// returns it. The handle of the entry is left for the caller to release or reuse.
+    ///
+    /// The point of an inner node is swapped with the minimum (along the node's direction) of its
+    /// right subtree, or with the maximum of its left subtree, until it has been moved down to a
+    /// leaf that can be unlinked.
+    fn unlink_path(&mut self, mut path: Vec<usize>) -> KDNode<P, V> {
+        for &index in &path {
+            self.nodes[index].size -= 1;
+        }
+        let mut target = *path.last().unwrap();
+        loop {
+            let node = &self.nodes[target];
+            let (subtree, ordering) = match (node.left, node.right) {
+                (_, Some(right)) => (right, Ordering::Less),
+                (Some(left), None) => (left, Ordering::Greater),
+                (None, None) => break,
+            };
+            let replacement = self.extreme_node(subtree, node.dir, ordering);
+            let start = path.len();
+            // the replacement was picked from the subtree, so it will always be found there
+            let _ = self.find_path(
+                subtree,
+                &self.nodes[replacement].pos,
+                |index| index == replacement,
+                &mut path,
+            );
+            for &index in &path[start..] {
+                self.nodes[index].size -= 1;
+            }
+            self.swap_entries(target, replacement);
+            target = replacement;
+        }
+
+        let parent = path.len().checked_sub(2).map(|depth| path[depth]);
+        *self.link_to(parent, target) = None;
 
-            root_node.recursive_closure_range_query(
-                pos,
-                radius * radius,
-                &mut cloned_rect,
-                &mut process,
+        // the last node will be moved into the slot of the removed node
+        let last = self.nodes.len() - 1;
+        if target != last {
+            path.clear();
+            let _ = self.find_path(
+                self.root.unwrap(),
+                &self.nodes[last].pos,
+                |index| index == last,
+                &mut path,
             );
+            let parent = path.len().checked_sub(2).map(|depth| path[depth]);
+            *self.link_to(parent, last) = Some(target);
+            self.slots[self.nodes[last].id] = Some(target);
         }
+        self.nodes.swap_remove(target)
     }
 
-    fn hyper_rect_dist_sq<Q: PointTrait<PScalar = P::PScalar>>(
-        rect: &HyperRectangle<P>,
-        pos: &Q,
-    ) -> P::PScalar {
-        let mut result = P::PScalar::zero();
-        for i in 0..P::DIMENSION {
-            let pos_val = pos.at(i);
-            if pos_val < rect.min.at(i) {
-                result += Self::sq(rect.min.at(i) - pos_val);
-            } else if pos_val > rect.max.at(i) {
-                result += Self::sq(rect.max.at(i) - pos_val);
+    /// Swaps the entries (point, value and id) of two nodes, leaving the tree structure
+    /// untouched.
+    fn swap_entries(&mut self, a: usize, b: usize) {
+        let (low, high) = (a.min(b), a.max(b));
+        let (head, tail) = self.nodes.split_at_mut(high);
+        let (low_node, high_node) = (&mut head[low], &mut tail[0]);
+        std::mem::swap(&mut low_node.pos, &mut high_node.pos);
+        std::mem::swap(&mut low_node.value, &mut high_node.value);
+        std::mem::swap(&mut low_node.id, &mut high_node.id);
+        self.slots[low_node.id] = Some(low);
+        self.slots[high_node.id] = Some(high);
+    }
+
+    /// Returns the node in the subtree rooted at `start` with the smallest (`Ordering::Less`)
+    /// or the largest (`Ordering::Greater`) coordinate along `axis`.
+    fn extreme_node(&self, start: usize, axis: u8, ordering: Ordering) -> usize {
+        let mut best = start;
+        let mut stack = vec![start];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node
+                .pos
+                .at(axis)
+                .partial_cmp(&self.nodes[best].pos.at(axis))
+                == Some(ordering)
+            {
+                best = index;
+            }
+            let (more_extreme, other) = match ordering {
+                Ordering::Less => (node.left, node.right),
+                _ => (node.right, node.left),
+            };
+            stack.extend(more_extreme);
+            // only one side of a split along `axis` can hold a more extreme node
+            if node.dir != axis {
+                stack.extend(other);
             }
         }
-        result
+        best
+    }
+
+    /// Returns the point closest to `pos` and its value, or `None` if the tree is empty.
+    pub fn nearest<Q: PointTrait<PScalar = P::PScalar>>(&self, pos: &Q) -> Option<(&P, &V)> {
+        self.nearest_node(
+            pos,
+            P::PScalar::one(),
+            None,
+            |_| true,
+            &mut QueryStats::default(),
+        )
+        .map(|nearest| self.entry(nearest.index))
+    }
+
+    /// Returns the point closest to `pos` and its value together with its squared distance to
+    /// `pos`, or `None` if the tree is empty.
+    pub fn nearest_with_distance<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+    ) -> Option<(P::PScalar, &P, &V)> {
+        self.nearest_node(
+            pos,
+            P::PScalar::one(),
+            None,
+            |_| true,
+            &mut QueryStats::default(),
+        )
+        .map(|nearest| {
+            let (pos, value) = self.entry(nearest.index);
+            (nearest.dist_sq, pos, value)
+        })
+    }
+
+    /// Returns the handle of the entry closest to `pos`, or `None` if the tree is empty.
+    pub fn nearest_id<Q: PointTrait<PScalar = P::PScalar>>(&self, pos: &Q) -> Option<PointId> {
+        self.nearest_node(
+            pos,
+            P::PScalar::one(),
+            None,
+            |_| true,
+            &mut QueryStats::default(),
+        )
+        .map(|nearest| PointId(self.nodes[nearest.index].id))
     }
 
     #[inline(always)]
-    fn sq(i: P::PScalar) -> P::PScalar {
+    fn entry(&self, index: usize) -> (&P, &V) {
+        let node = &self.nodes[index];
+        (&node.pos, &node.value)
+    }
+
+    /// Returns the point closest to `pos` and its value like [`KDMap::nearest`], together with
+    /// statistics about the search.
+    pub fn nearest_with_stats<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+    ) -> (Option<(&P, &V)>, QueryStats) {
+        let mut stats = QueryStats::default();
+        let result = self.nearest_node(pos, P::PScalar::one(), None, |_| true, &mut stats);
+        (result.map(|nearest| self.entry(nearest.index)), stats)
+    }
+
+    /// Returns the node closest to `pos` among the nodes for which `accept` returns `true`,
+    /// and only if it is within the squared distance `limit_sq`.
+    /// Cells are pruned once their squared distance times `scale` reaches that of the best
+    /// candidate, so any `scale` above one makes the search approximate.
+    fn nearest_node<Q: PointTrait<PScalar = P::PScalar>, F>(
+        &self,
+        pos: &Q,
+        scale: P::PScalar,
+        limit_sq: Option<P::PScalar>,
+        mut accept: F,
+        stats: &mut QueryStats,
+    ) -> Option<Candidate<P::PScalar>>
+    where
+        F: FnMut(usize) -> bool,
+    {
+        // until a candidate is found only the limit bounds the search
+        let beats = |result: &Option<Candidate<P::PScalar>>, dist_sq| match result {
+            Some(best) => dist_sq < best.dist_sq,
+            None => limit_sq.is_none_or(|limit_sq| dist_sq <= limit_sq),
+        };
+        if !beats(
+            &None,
+            Self::hyper_rect_dist_sq(self.rect.as_ref()?, pos) * scale,
+        ) {
+            return None;
+        }
+        let mut walk = CellWalk::new(self, pos)?;
+        let mut result: Option<Candidate<P::PScalar>> = None;
+        while let Some(index) =
+            walk.next(|cell| beats(&result, Self::hyper_rect_dist_sq(cell, pos) * scale))
+        {
+            let dist_sq = PointTrait::dist_sq(&self.nodes[index].pos, pos);
+            if beats(&result, dist_sq) && accept(index) {
+                result = Some(Candidate { dist_sq, index });
+            }
+        }
+        *stats = walk.stats();
+        result
+    }
+
+    /// Returns an iterator over all the points and their values in increasing distance from
+    /// `pos`, each with its squared distance to `pos`.
+    ///
+    /// The tree is searched lazily as the iterator advances, so taking only the first few
+    /// items costs about as much as a [`KDMap::nearest`] query.
+    pub fn nearest_iter<'a, Q: PointTrait<PScalar = P::PScalar>>(
+        &'a self,
+        pos: &'a Q,
+    ) -> NearestIter<'a, P, V, Q> {
+        let mut iter = NearestIter {
+            map: self,
+            pos,
+            queue: BinaryHeap::new(),
+        };
+        if let (Some(root), Some(rect)) = (self.root, self.rect.clone()) {
+            iter.push_subtree(root, rect);
+        }
+        iter
+    }
+
+    /// Returns the point closest to `pos` and its value together with its squared distance to
+    /// `pos`, but only if it is within `max_dist` of `pos`.
+    ///
+    /// The search is bounded by `max_dist` from the start, so it gives up early when there is
+    /// nothing near `pos`.
+    pub fn nearest_within<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        max_dist: P::PScalar,
+    ) -> Option<(P::PScalar, &P, &V)> {
+        let limit_sq = Some(max_dist * max_dist);
+        self.nearest_node(
+            pos,
+            P::PScalar::one(),
+            limit_sq,
+            |_| true,
+            &mut QueryStats::default(),
+        )
+        .map(|nearest| {
+            let (pos, value) = self.entry(nearest.index);
+            (nearest.dist_sq, pos, value)
+        })
+    }
+
+    /// Returns the `k` points closest to `pos` and their values, nearest first, each with its
+    /// squared distance to `pos`. Fewer than `k` entries are returned if the tree is smaller.
+    pub fn nearest_k<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        k: usize,
+    ) -> Vec<(P::PScalar, &P, &V)> {
+        self.nearest_k_nodes(pos, k, P::PScalar::one(), |_| true)
+            .into_iter()
+            .map(|candidate| {
+                let (pos, value) = self.entry(candidate.index);
+                (candidate.dist_sq, pos, value)
+            })
+            .collect()
+    }
+
+    /// Returns the point closest to `pos` and its value, or an approximation of it, together
+    /// with its squared distance to `pos`. Returns `None` if the tree is empty.
+    ///
+    /// The distance to the returned point is at most (1 + `epsilon`) times the distance to the
+    /// true nearest point, which lets the search skip subtrees that could only improve on the
+    /// result by less than that. An `epsilon` of zero gives the exact nearest point, negative
+    /// values are treated as zero.
+    pub fn nearest_approx<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        epsilon: P::PScalar,
+    ) -> Option<(P::PScalar, &P, &V)> {
+        let scale = Self::approx_scale(epsilon);
+        self.nearest_node(pos, scale, None, |_| true, &mut QueryStats::default())
+            .map(|nearest| {
+                let (pos, value) = self.entry(nearest.index);
+                (nearest.dist_sq, pos, value)
+            })
+    }
+
+    /// Returns `k` points close to `pos` and their values like [`KDMap::nearest_k`], but the
+    /// n:th returned point is only guaranteed to be within (1 + `epsilon`) times the distance of
+    /// the true n:th nearest point. See [`KDMap::nearest_approx`].
+    pub fn nearest_k_approx<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        k: usize,
+        epsilon: P::PScalar,
+    ) -> Vec<(P::PScalar, &P, &V)> {
+        self.nearest_k_nodes(pos, k, Self::approx_scale(epsilon), |_| true)
+            .into_iter()
+            .map(|candidate| {
+                let (pos, value) = self.entry(candidate.index);
+                (candidate.dist_sq, pos, value)
+            })
+            .collect()
+    }
+
+    /// Returns the point closest to `pos` for which `accept` returns `true`, its value and its
+    /// squared distance to `pos`, or `None` if no entry is accepted.
+    ///
+    /// Every rejected point closer than the result has to be visited, so to skip the entry a
+    /// query is made for, prefer [`KDMap::nearest_excluding`] over rejecting its position:
+    /// that stays cheap even if many other entries share the same position.
+    pub fn nearest_filtered<Q: PointTrait<PScalar = P::PScalar>, F>(
+        &self,
+        pos: &Q,
+        mut accept: F,
+    ) -> Option<(P::PScalar, &P, &V)>
+    where
+        F: FnMut(&P, &V) -> bool,
+    {
+        let accept = |index| {
+            let (pos, value) = self.entry(index);
+            accept(pos, value)
+        };
+        self.nearest_node(
+            pos,
+            P::PScalar::one(),
+            None,
+            accept,
+            &mut QueryStats::default(),
+        )
+        .map(|nearest| {
+            let (pos, value) = self.entry(nearest.index);
+            (nearest.dist_sq, pos, value)
+        })
+    }
+
+    /// Returns the `k` points closest to `pos` for which `accept` returns `true` like
+    /// [`KDMap::nearest_k`], see [`KDMap::nearest_filtered`].
+    pub fn nearest_k_filtered<Q: PointTrait<PScalar = P::PScalar>, F>(
+        &self,
+        pos: &Q,
+        k: usize,
+        mut accept: F,
+    ) -> Vec<(P::PScalar, &P, &V)>
+    where
+        F: FnMut(&P, &V) -> bool,
+    {
+        let accept = |index| {
+            let (pos, value) = self.entry(index);
+            accept(pos, value)
+        };
+        self.nearest_k_nodes(pos, k, P::PScalar::one(), accept)
+            .into_iter()
+            .map(|candidate| {
+                let (pos, value) = self.entry(candidate.index);
+                (candidate.dist_sq, pos, value)
+            })
+            .collect()
+    }
+
+    /// Returns the entry closest to the entry with the handle `id`, other than that entry
+    /// itself, together with its squared distance. Returns `None` if there is no entry `id`
+    /// or no other entry.
+    pub fn nearest_excluding(&self, id: PointId) -> Option<(P::PScalar, &P, &V)> {
+        let pos = &self.nodes[self.node_index(id)?].pos;
+        let accept = |index: usize| self.nodes[index].id != id.0;
+        self.nearest_node(
+            pos,
+            P::PScalar::one(),
+            None,
+            accept,
+            &mut QueryStats::default(),
+        )
+        .map(|nearest| {
+            let (pos, value) = self.entry(nearest.index);
+            (nearest.dist_sq, pos, value)
+        })
+    }
+
+    /// Converts the `epsilon` of an approximate query to the factor applied to squared cell
+    /// distances, (1 + ε)²
+    fn approx_scale(epsilon: P::PScalar) -> P::PScalar {
+        Self::sq(P::PScalar::one() + epsilon.max(P::PScalar::zero()))
+    }
+
+    /// Returns the `k` nodes closest to `pos`, nearest first, see `nearest_node()` for `scale`
+    /// and `accept`.
+    ///
+    /// The candidates are kept in a max-heap of at most `k` entries, so once it is full the
+    /// farthest candidate bounds the cells still worth walking into.
+    fn nearest_k_nodes<Q: PointTrait<PScalar = P::PScalar>, F>(
+        &self,
+        pos: &Q,
+        k: usize,
+        scale: P::PScalar,
+        accept: F,
+    ) -> Vec<Candidate<P::PScalar>>
+    where
+        F: FnMut(usize) -> bool,
+    {
+        self.nearest_k_nodes_by(
+            CellWalk::new(self, pos),
+            k,
+            |point| PointTrait::dist_sq(point, pos),
+            |cell| Some(Self::hyper_rect_dist_sq(cell, pos) * scale),
+            accept,
+        )
+    }
+
+    /// Same as `nearest_k_nodes()`, but for any measure of distance: `dist_sq` gives it for a
+    /// point, and `cell_dist_sq` a lower bound of it for all the points inside a cell, or `None`
+    /// if no point inside the cell can be accepted.
+    ///
+    /// Unless the cell of a node is as near as the cell of its parent, as it is for the
+    /// nearer subtree with the euclidean distance, `walk` has to be a [`CellWalk::checked`] one.
+    fn nearest_k_nodes_by<Q, D, C, F>(
+        &self,
+        walk: Option<CellWalk<'_, P, V, Q>>,
+        k: usize,
+        dist_sq: D,
+        cell_dist_sq: C,
+        mut accept: F,
+    ) -> Vec<Candidate<P::PScalar>>
+    where
+        Q: PointTrait<PScalar = P::PScalar>,
+        D: Fn(&P) -> P::PScalar,
+        C: Fn(&HyperRectangle<P>) -> Option<P::PScalar>,
+        F: FnMut(usize) -> bool,
+    {
+        if k == 0 {
+            return Vec::new();
+        }
+        let Some(mut walk) = walk else {
+            return Vec::new();
+        };
+        let mut heap: BinaryHeap<Candidate<P::PScalar>> = BinaryHeap::with_capacity(k + 1);
+        while let Some(index) = walk.next(|cell| match cell_dist_sq(cell) {
+            Some(cell_dist_sq) => heap.len() < k || cell_dist_sq < heap.peek().unwrap().dist_sq,
+            None => false,
+        }) {
+            let dist_sq = dist_sq(&self.nodes[index].pos);
+            if heap.len() < k {
+                if accept(index) {
+                    heap.push(Candidate { dist_sq, index });
+                }
+            } else if dist_sq < heap.peek().unwrap().dist_sq && accept(index) {
+                let _ = heap.pop();
+                heap.push(Candidate { dist_sq, index });
+            }
+        }
+        heap.into_sorted_vec()
+    }
+
+    /// Returns the `k` points closest to the segment from `a` to `b` and their values, nearest
+    /// first, each with its squared distance to the segment. Fewer than `k` entries are
+    /// returned if the tree is smaller.
+    pub fn nearest_to_segment(&self, a: &P, b: &P, k: usize) -> Vec<(P::PScalar, &P, &V)> {
+        let half = P::PScalar::one() / (P::PScalar::one() + P::PScalar::one());
+        let mut middle = a.clone();
+        middle.set_x(a.x() + (b.x() - a.x()) * half);
+        middle.set_y(a.y() + (b.y() - a.y()) * half);
+        self.nearest_k_nodes_by(
+            CellWalk::checked(self, &middle),
+            k,
+            |point| region::segment_dist_sq(a, b, point),
+            |cell| Some(region::segment_rect_dist_sq(a, b, cell)),
+            |_| true,
+        )
+        .into_iter()
+        .map(|candidate| {
+            let (pos, value) = self.entry(candidate.index);
+            (candidate.dist_sq, pos, value)
+        })
+        .collect()
+    }
+
+    /// Returns the point closest to `origin` inside the cone around `direction` (see
+    /// [`Cone`](region::Cone)), its value and its squared distance to `origin`, or `None` if
+    /// the cone is empty.
+    pub fn nearest_in_cone(
+        &self,
+        origin: &P,
+        direction: &P,
+        half_angle: P::PScalar,
+    ) -> Option<(P::PScalar, &P, &V)> {
+        self.nearest_in_region(
+            origin,
+            &region::Cone::new(origin.clone(), direction.clone(), half_angle),
+        )
+    }
+
+    /// Returns the point closest to `origin` that lies ahead of it, i.e. has a positive dot
+    /// product with `direction` relative to `origin`, its value and its squared distance to
+    /// `origin`, or `None` if there is no such point.
+    pub fn nearest_ahead(&self, origin: &P, direction: &P) -> Option<(P::PScalar, &P, &V)> {
+        self.nearest_in_region(
+            origin,
+            &region::Cone::ahead(origin.clone(), direction.clone()),
+        )
+    }
+
+    /// Returns the point within `tolerance` of the ray from `origin` along `dir` that the ray
+    /// reaches first, its value and its parameter `t`, or `None` if the ray passes no point.
+    ///
+    /// `t` is the position of the point projected onto the ray in multiples of `dir`, which
+    /// does not need to be normalized. Points within `tolerance` behind `origin` get a `t` of
+    /// zero. The cells are searched front to back along the ray, and cells the ray can only
+    /// reach after the best hit so far are skipped.
+    pub fn ray_cast(
+        &self,
+        origin: &P,
+        dir: &P,
+        tolerance: P::PScalar,
+    ) -> Option<(P::PScalar, &P, &V)> {
+        let zero = P::PScalar::zero();
+        if tolerance < zero {
+            return None;
+        }
+        let (dx, dy) = (dir.x(), dir.y());
+        let length_sq = dx * dx + dy * dy;
+        // the parameter of the point on the ray closest to `pos`
+        let ray_t = |pos: &P| {
+            if length_sq > zero {
+                (((pos.x() - origin.x()) * dx + (pos.y() - origin.y()) * dy) / length_sq).max(zero)
+            } else {
+                zero
+            }
+        };
+        let tolerance_sq = tolerance * tolerance;
+        let hit = |pos: &P| {
+            let t = ray_t(pos);
+            Self::sq(origin.x() + t * dx - pos.x()) + Self::sq(origin.y() + t * dy - pos.y())
+                <= tolerance_sq
+        };
+        self.nearest_k_nodes_by(
+            CellWalk::checked(self, origin),
+            1,
+            ray_t,
+            |cell| {
+                // a point inside the cell is within tolerance of the ray only where the ray
+                // passes through the cell grown by the tolerance
+                let mut grown = cell.clone();
+                for i in 0..P::DIMENSION {
+                    *grown.min.at_mut(i) -= tolerance;
+                    *grown.max.at_mut(i) += tolerance;
+                }
+                region::clip_to_rect(origin, dx, dy, P::PScalar::max_value(), &grown)
+                    .map(|(t0, _)| t0)
+            },
+            |index| hit(&self.nodes[index].pos),
+        )
+        .pop()
+        .map(|first| {
+            let (pos, value) = self.entry(first.index);
+            (first.dist_sq, pos, value)
+        })
+    }
+
+    /// Returns the point closest to `pos` inside `region`, skipping the cells outside of it.
+    fn nearest_in_region<R: Region<P>>(&self, pos: &P, region: &R) -> Option<(P::PScalar, &P, &V)> {
+        self.nearest_k_nodes_by(
+            CellWalk::checked(self, pos),
+            1,
+            |point| PointTrait::dist_sq(point, pos),
+            |cell| {
+                region
+                    .intersects(cell)
+                    .then(|| Self::hyper_rect_dist_sq(cell, pos))
+            },
+            |index| region.contains(&self.nodes[index].pos),
+        )
+        .pop()
+        .map(|nearest| {
+            let (pos, value) = self.entry(nearest.index);
+            (nearest.dist_sq, pos, value)
+        })
+    }
+
+    /// Returns the point farthest from `pos` and its value together with its squared distance
+    /// to `pos`, or `None` if the tree is empty.
+    pub fn farthest<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+    ) -> Option<(P::PScalar, &P, &V)> {
+        self.farthest_k_nodes(pos, 1).pop().map(|farthest| {
+            let (pos, value) = self.entry(farthest.index);
+            (farthest.dist_sq, pos, value)
+        })
+    }
+
+    /// Returns the `k` points farthest from `pos` and their values, farthest first, each with
+    /// its squared distance to `pos`. Fewer than `k` entries are returned if the tree is smaller.
+    pub fn farthest_k<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        k: usize,
+    ) -> Vec<(P::PScalar, &P, &V)> {
+        self.farthest_k_nodes(pos, k)
+            .into_iter()
+            .map(|candidate| {
+                let (pos, value) = self.entry(candidate.index);
+                (candidate.dist_sq, pos, value)
+            })
+            .collect()
+    }
+
+    /// Returns the `k` nodes farthest from `pos`, farthest first.
+    ///
+    /// The mirror image of `nearest_k_nodes()`: the candidates are kept in a min-heap, and a cell
+    /// is skipped once even its corner farthest from `pos` is no farther than the nearest
+    /// candidate of a full heap.
+    fn farthest_k_nodes<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        k: usize,
+    ) -> Vec<Candidate<P::PScalar>> {
+        if k == 0 {
+            return Vec::new();
+        }
+        let Some(mut walk) = CellWalk::farthest_first(self, pos) else {
+            return Vec::new();
+        };
+        let mut heap: BinaryHeap<Reverse<Candidate<P::PScalar>>> = BinaryHeap::with_capacity(k + 1);
+        while let Some(index) = walk.next(|cell| {
+            heap.len() < k
+                || Self::hyper_rect_max_dist_sq(cell, pos) > heap.peek().unwrap().0.dist_sq
+        }) {
+            let dist_sq = PointTrait::dist_sq(&self.nodes[index].pos, pos);
+            if heap.len() < k {
+                heap.push(Reverse(Candidate { dist_sq, index }));
+            } else if dist_sq > heap.peek().unwrap().0.dist_sq {
+                let _ = heap.pop();
+                heap.push(Reverse(Candidate { dist_sq, index }));
+            }
+        }
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(candidate)| candidate)
+            .collect()
+    }
+
+    /// Returns all the points within `radius` of `pos`, together with their values.
+    pub fn range_query<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+    ) -> Vec<(&P, &V)> {
+        let mut results = Vec::new();
+        self.closure_range_query_indices(pos, radius, |index, _| results.push(self.entry(index)));
+        results
+    }
+
+    /// Calls `process` with every point within `radius` of `pos` and its value.
+    pub fn closure_range_query<Q: PointTrait<PScalar = P::PScalar>, F>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+        mut process: F,
+    ) where
+        F: FnMut(&P, &V),
+    {
+        self.closure_range_query_indices(pos, radius, |index, _| {
+            let (pos, value) = self.entry(index);
+            process(pos, value)
+        });
+    }
+
+    /// Calls `process` with every point within `radius` of `pos` and its value, until `process`
+    /// returns [`ControlFlow::Break`]. The break value is returned, and no more of the tree is
+    /// searched once `process` breaks.
+    pub fn try_closure_range_query<Q: PointTrait<PScalar = P::PScalar>, B, F>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+        mut process: F,
+    ) -> ControlFlow<B>
+    where
+        F: FnMut(&P, &V) -> ControlFlow<B>,
+    {
+        self.try_closure_range_query_indices(pos, radius, |index, _| {
+            let (pos, value) = self.entry(index);
+            process(pos, value)
+        })
+    }
+
+    /// Returns the number of points within `radius` of `pos`.
+    ///
+    /// Subtrees whose cell lies entirely inside the circle are counted as a whole, without
+    /// visiting their nodes.
+    pub fn count_within<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+    ) -> usize {
+        let (Some(root), Some(rect)) = (self.root, self.rect.clone()) else {
+            return 0;
+        };
+        let radius_sq = radius * radius;
+        let mut count = 0;
+        let mut stack = vec![(root, rect)];
+        while let Some((index, mut cell)) = stack.pop() {
+            let node = &self.nodes[index];
+            if Self::hyper_rect_dist_sq(&cell, pos) > radius_sq {
+                continue;
+            }
+            if Self::hyper_rect_max_dist_sq(&cell, pos) <= radius_sq {
+                count += node.size;
+                continue;
+            }
+            if PointTrait::dist_sq(&node.pos, pos) <= radius_sq {
+                count += 1;
+            }
+            let split = node.pos.at(node.dir);
+            if let Some(left) = node.left {
+                let mut left_cell = cell.clone();
+                *left_cell.max.at_mut(node.dir) = split;
+                stack.push((left, left_cell));
+            }
+            if let Some(right) = node.right {
+                *cell.min.at_mut(node.dir) = split;
+                stack.push((right, cell));
+            }
+        }
+        count
+    }
+
+    /// Returns `true` if there is at least one point within `radius` of `pos`. The search stops
+    /// at the first such point.
+    pub fn any_within<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+    ) -> bool {
+        let Some(mut walk) = CellWalk::new(self, pos) else {
+            return false;
+        };
+        let radius_sq = radius * radius;
+        while let Some(index) = walk.next(|cell| Self::hyper_rect_dist_sq(cell, pos) <= radius_sq) {
+            if PointTrait::dist_sq(&self.nodes[index].pos, pos) <= radius_sq {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Returns the handles of all the entries within `radius` of `pos`.
+    pub fn range_query_ids<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+    ) -> Vec<PointId> {
+        let mut results = Vec::new();
+        self.closure_range_query_indices(pos, radius, |index, _| {
+            results.push(PointId(self.nodes[index].id))
+        });
+        results
+    }
+
+    /// Returns all the points inside the axis aligned box spanned by `min` and `max`, edges
+    /// included, together with their values.
+    pub fn rect_query(&self, min: &P, max: &P) -> Vec<(&P, &V)> {
+        let mut results = Vec::new();
+        self.closure_rect_query_indices(min, max, |index| results.push(self.entry(index)));
+        results
+    }
+
+    /// Calls `process` with every point inside the axis aligned box spanned by `min` and `max`,
+    /// edges included, and its value.
+    pub fn closure_rect_query<F>(&self, min: &P, max: &P, mut process: F)
+    where
+        F: FnMut(&P, &V),
+    {
+        self.closure_rect_query_indices(min, max, |index| {
+            let (pos, value) = self.entry(index);
+            process(pos, value)
+        });
+    }
+
+    fn closure_rect_query_indices<F>(&self, min: &P, max: &P, process: F)
+    where
+        F: FnMut(usize),
+    {
+        let query = HyperRectangle::new(min.clone(), max.clone());
+        self.closure_region_query_indices(&query, Some(min), process)
+    }
+
+    /// Returns all the points inside `region`, together with their values.
+    pub fn region_query<R: Region<P> + ?Sized>(&self, region: &R) -> Vec<(&P, &V)> {
+        let mut results = Vec::new();
+        self.closure_region_query_indices(region, None, |index| results.push(self.entry(index)));
+        results
+    }
+
+    /// Calls `process` with every point inside `region` and its value.
+    pub fn closure_region_query<R: Region<P> + ?Sized, F>(&self, region: &R, mut process: F)
+    where
+        F: FnMut(&P, &V),
+    {
+        self.closure_region_query_indices(region, None, |index| {
+            let (pos, value) = self.entry(index);
+            process(pos, value)
+        });
+    }
+
+    /// Calls `process` once with every point within `radius` of the segment from `a` to `b`,
+    /// and its value.
+    pub fn segment_range_query<F>(&self, a: &P, b: &P, radius: P::PScalar, mut process: F)
+    where
+        F: FnMut(&P, &V),
+    {
+        let capsule = region::Capsule::new(a.clone(), b.clone(), radius);
+        self.closure_region_query_indices(&capsule, Some(a), |index| {
+            let (pos, value) = self.entry(index);
+            process(pos, value)
+        });
+    }
+
+    /// Calls `process` once with every point within `radius` of the polyline through `path`,
+    /// and its value. Points near several segments of the path are still only reported once.
+    pub fn polyline_range_query<F>(&self, path: &[P], radius: P::PScalar, mut process: F)
+    where
+        F: FnMut(&P, &V),
+    {
+        let capsule = region::Capsule::from_path(path.to_vec(), radius);
+        self.closure_region_query_indices(&capsule, path.first(), |index| {
+            let (pos, value) = self.entry(index);
+            process(pos, value)
+        });
+    }
+
+    /// Calls `process` with the index of every node inside `region`. The walk enters the side
+    /// of every split facing `towards` first, or the side of the root point if there is none.
+    fn closure_region_query_indices<R: Region<P> + ?Sized, F>(
+        &self,
+        region: &R,
+        towards: Option<&P>,
+        mut process: F,
+    ) where
+        F: FnMut(usize),
+    {
+        let Some(root) = self.root else {
+            return;
+        };
+        // both subtrees are checked against the region, so the order only changes which of
+        // them is walked first
+        let towards = towards.unwrap_or(&self.nodes[root].pos);
+        let Some(mut walk) = CellWalk::checked(self, towards) else {
+            return;
+        };
+        while let Some(index) = walk.next(|cell| region.intersects(cell)) {
+            if region.contains(&self.nodes[index].pos) {
+                process(index);
+            }
+        }
+    }
+
+    /// Calls `process` with the index of every node within `radius` of `pos`, and the squared
+    /// distance of its point to `pos`.
+    fn closure_range_query_indices<Q: PointTrait<PScalar = P::PScalar>, F>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+        mut process: F,
+    ) where
+        F: FnMut(usize, P::PScalar),
+    {
+        let _ = self.try_closure_range_query_indices(pos, radius, |index, dist_sq| {
+            process(index, dist_sq);
+            ControlFlow::<()>::Continue(())
+        });
+    }
+
+    /// Same as `closure_range_query_indices()`, but stops as soon as `process` breaks.
+    fn try_closure_range_query_indices<Q: PointTrait<PScalar = P::PScalar>, B, F>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+        mut process: F,
+    ) -> ControlFlow<B>
+    where
+        F: FnMut(usize, P::PScalar) -> ControlFlow<B>,
+    {
+        let Some(mut walk) = CellWalk::new(self, pos) else {
+            return ControlFlow::Continue(());
+        };
+        let radius_sq = radius * radius;
+        while let Some(index) = walk.next(|cell| Self::hyper_rect_dist_sq(cell, pos) <= radius_sq) {
+            let dist_sq = PointTrait::dist_sq(&self.nodes[index].pos, pos);
+            if dist_sq <= radius_sq {
+                process(index, dist_sq)?;
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn format_nodes(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut stack: Vec<(usize, usize)> = self.root.map(|root| (root, 0)).into_iter().collect();
+        while let Some((index, depth)) = stack.pop() {
+            let node = &self.nodes[index];
+            for _ in 0..depth {
+                write!(f, " ")?;
+            }
+
+            write!(f, "d={} node at ", node.dir)?;
+            for i in 0..P::DIMENSION {
+                write!(f, "{} ", node.pos.at(i))?;
+            }
+            writeln!(f)?;
+
+            stack.extend(node.right.map(|child| (child, depth + 1)));
+            stack.extend(node.left.map(|child| (child, depth + 1)));
+        }
+        Ok(())
+    }
+
+    fn hyper_rect_dist_sq<Q: PointTrait<PScalar = P::PScalar>>(
+        rect: &HyperRectangle<P>,
+        pos: &Q,
+    ) -> P::PScalar {
+        let mut result = P::PScalar::zero();
+        for i in 0..P::DIMENSION {
+            let pos_val = pos.at(i);
+            if pos_val < rect.min.at(i) {
+                result += Self::sq(rect.min.at(i) - pos_val);
+            } else if pos_val > rect.max.at(i) {
+                result += Self::sq(rect.max.at(i) - pos_val);
+            }
+        }
+        result
+    }
+
+    /// Returns the squared distance from `pos` to the point of `rect` farthest from it.
+    fn hyper_rect_max_dist_sq<Q: PointTrait<PScalar = P::PScalar>>(
+        rect: &HyperRectangle<P>,
+        pos: &Q,
+    ) -> P::PScalar {
+        let mut result = P::PScalar::zero();
+        for i in 0..P::DIMENSION {
+            let pos_val = pos.at(i);
+            result += Self::sq((pos_val - rect.min.at(i)).max(rect.max.at(i) - pos_val));
+        }
+        result
+    }
+
+    #[inline(always)]
+    fn sq(i: P::PScalar) -> P::PScalar {
         i * i
     }
 }
+
+impl<P: PointTrait> KDTree<P> {
+    /// Builds a balanced tree from `points` in O(n log n).
+    ///
+    /// The resulting shape does not depend on the order of `points`, so this is the preferred
+    /// way of populating a tree when all the points are known up front.
+    pub fn from_points(points: Vec<P>) -> Self {
+        Self {
+            map: KDMap::from_entries(points.into_iter().map(|pos| (pos, ())).collect()),
+        }
+    }
+
+    /// Creates an empty tree that keeps itself balanced while points are inserted one at a time,
+    /// see [`KDMap::with_balancing`].
+    pub fn with_balancing(alpha: f64) -> Result<Self, KrakelError> {
+        Ok(Self {
+            map: KDMap::with_balancing(alpha)?,
+        })
+    }
+
+    /// Builds the same balanced tree as [`KDTree::from_points`], but splits the work over the
+    /// rayon thread pool.
+    #[cfg(feature = "rayon")]
+    pub fn par_from_points(points: Vec<P>) -> Self
+    where
+        P: Send,
+    {
+        Self {
+            map: KDMap::par_from_entries(points.into_iter().map(|pos| (pos, ())).collect()),
+        }
+    }
+
+    /// Inserts `pos` and returns the handle of the new entry.
+    pub fn insert(&mut self, pos: P) -> Result<PointId, KrakelError> {
+        self.map.insert(pos, ())
+    }
+
+    /// Moves one point equal to `old` to `pos` and returns its handle, or `None` if no such
+    /// point was found. See [`KDMap::update`].
+    pub fn update(&mut self, old: &P, pos: P) -> Option<PointId> {
+        self.map.update(old, pos)
+    }
+
+    /// Moves the point with the handle `id` to `pos` and returns its previous position, or
+    /// `None` if there is no such point.
+    pub fn update_by_id(&mut self, id: PointId, pos: P) -> Option<P> {
+        self.map.update_by_id(id, pos)
+    }
+
+    /// Removes every point within `radius` of `pos` and returns them.
+    pub fn remove_within<Q: PointTrait<PScalar = P::PScalar>>(
+        &mut self,
+        pos: &Q,
+        radius: P::PScalar,
+    ) -> Vec<P> {
+        self.map
+            .remove_within(pos, radius)
+            .into_iter()
+            .map(|(pos, _)| pos)
+            .collect()
+    }
+
+    /// Keeps only the points for which `keep` returns `true`, see [`KDMap::retain`].
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&P) -> bool,
+    {
+        self.map.retain(|pos, _| keep(pos))
+    }
+
+    /// Removes all the points from the tree and returns them, in no particular order.
+    pub fn drain(&mut self) -> Vec<P> {
+        self.map.drain().into_iter().map(|(pos, _)| pos).collect()
+    }
+
+    /// Removes all the points from the tree.
+    pub fn clear(&mut self) {
+        self.map.clear()
+    }
+
+    /// Returns the point with the handle `id`.
+    pub fn get(&self, id: PointId) -> Option<&P> {
+        self.map.get(id).map(|(pos, _)| pos)
+    }
+
+    /// Removes the point with the handle `id` and returns it, or `None` if there is no such
+    /// point.
+    pub fn remove_by_id(&mut self, id: PointId) -> Option<P> {
+        self.map.remove_by_id(id).map(|(pos, _)| pos)
+    }
+
+    /// Returns the number of points in the tree.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns statistics about the shape of the tree. This walks the whole tree.
+    pub fn stats(&self) -> KDTreeStats {
+        self.map.stats()
+    }
+
+    /// Returns the number of nodes on the longest path from the root to a leaf.
+    pub fn depth(&self) -> usize {
+        self.map.depth()
+    }
+
+    /// Removes one point equal to `pos` from the tree and returns it, or `None` if no such point
+    /// was found.
+    ///
+    /// The bounding rectangle of the tree is not shrunk, so it stays a conservative bound.
+    pub fn remove(&mut self, pos: &P) -> Option<P> {
+        self.map.remove(pos).map(|(pos, _)| pos)
+    }
+
+    /// Removes and returns the point closest to `pos`, or `None` if the tree is empty.
+    pub fn remove_nearest<Q: PointTrait<PScalar = P::PScalar>>(&mut self, pos: &Q) -> Option<P> {
+        self.map.remove_nearest(pos).map(|(pos, _)| pos)
+    }
+
+    /// Returns a copy of the point closest to `pos`, or `None` if the tree is empty.
+    #[allow(dead_code)]
+    pub fn nearest<Q: PointTrait<PScalar = P::PScalar>>(&self, pos: &Q) -> Option<P> {
+        self.nearest_ref(pos).cloned()
+    }
+
+    /// Returns the point closest to `pos`, or `None` if the tree is empty.
+    pub fn nearest_ref<Q: PointTrait<PScalar = P::PScalar>>(&self, pos: &Q) -> Option<&P> {
+        self.map.nearest(pos).map(|(pos, _)| pos)
+    }
+
+    /// Returns the point closest to `pos` together with its squared distance to `pos`, or
+    /// `None` if the tree is empty.
+    pub fn nearest_with_distance<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+    ) -> Option<(P::PScalar, &P)> {
+        self.map
+            .nearest_with_distance(pos)
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+    }
+
+    /// Returns the handle of the point closest to `pos`, or `None` if the tree is empty.
+    pub fn nearest_id<Q: PointTrait<PScalar = P::PScalar>>(&self, pos: &Q) -> Option<PointId> {
+        self.map.nearest_id(pos)
+    }
+
+    /// Returns the point closest to `pos` together with statistics about the search, see
+    /// [`KDMap::nearest_with_stats`].
+    pub fn nearest_with_stats<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+    ) -> (Option<P>, QueryStats) {
+        let (result, stats) = self.map.nearest_with_stats(pos);
+        (result.map(|(pos, _)| pos.clone()), stats)
+    }
+
+    /// Returns the handles of all the points within `radius` of `pos`.
+    pub fn range_query_ids<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+    ) -> Vec<PointId> {
+        self.map.range_query_ids(pos, radius)
+    }
+
+    /// Returns an iterator over all the points in increasing distance from `pos`, each with its
+    /// squared distance to `pos`, see [`KDMap::nearest_iter`].
+    pub fn nearest_iter<'a, Q: PointTrait<PScalar = P::PScalar>>(
+        &'a self,
+        pos: &'a Q,
+    ) -> impl Iterator<Item = (P::PScalar, &'a P)> + 'a {
+        self.map
+            .nearest_iter(pos)
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+    }
+
+    /// Returns the `k` points closest to `pos`, nearest first, each with its squared distance
+    /// to `pos`. Fewer than `k` points are returned if the tree is smaller.
+    pub fn nearest_k<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        k: usize,
+    ) -> Vec<(P::PScalar, &P)> {
+        self.map
+            .nearest_k(pos, k)
+            .into_iter()
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+            .collect()
+    }
+
+    /// Returns the point closest to `pos`, or an approximation of it, together with its squared
+    /// distance to `pos`, see [`KDMap::nearest_approx`].
+    pub fn nearest_approx<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        epsilon: P::PScalar,
+    ) -> Option<(P::PScalar, &P)> {
+        self.map
+            .nearest_approx(pos, epsilon)
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+    }
+
+    /// Returns `k` points close to `pos`, nearest first, each with its squared distance to
+    /// `pos`, see [`KDMap::nearest_k_approx`].
+    pub fn nearest_k_approx<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        k: usize,
+        epsilon: P::PScalar,
+    ) -> Vec<(P::PScalar, &P)> {
+        self.map
+            .nearest_k_approx(pos, k, epsilon)
+            .into_iter()
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+            .collect()
+    }
+
+    /// Returns the point closest to `pos` together with its squared distance to `pos`, but only
+    /// if it is within `max_dist` of `pos`, see [`KDMap::nearest_within`].
+    pub fn nearest_within<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        max_dist: P::PScalar,
+    ) -> Option<(P::PScalar, &P)> {
+        self.map
+            .nearest_within(pos, max_dist)
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+    }
+
+    /// Returns the point closest to `pos` for which `accept` returns `true` together with its
+    /// squared distance to `pos`, see [`KDMap::nearest_filtered`].
+    pub fn nearest_filtered<Q: PointTrait<PScalar = P::PScalar>, F>(
+        &self,
+        pos: &Q,
+        mut accept: F,
+    ) -> Option<(P::PScalar, &P)>
+    where
+        F: FnMut(&P) -> bool,
+    {
+        self.map
+            .nearest_filtered(pos, |pos, _| accept(pos))
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+    }
+
+    /// Returns the `k` points closest to `pos` for which `accept` returns `true`, nearest
+    /// first, each with its squared distance to `pos`.
+    pub fn nearest_k_filtered<Q: PointTrait<PScalar = P::PScalar>, F>(
+        &self,
+        pos: &Q,
+        k: usize,
+        mut accept: F,
+    ) -> Vec<(P::PScalar, &P)>
+    where
+        F: FnMut(&P) -> bool,
+    {
+        self.map
+            .nearest_k_filtered(pos, k, |pos, _| accept(pos))
+            .into_iter()
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+            .collect()
+    }
+
+    /// Returns the point closest to the point with the handle `id`, other than that point
+    /// itself, see [`KDMap::nearest_excluding`].
+    pub fn nearest_excluding(&self, id: PointId) -> Option<(P::PScalar, &P)> {
+        self.map
+            .nearest_excluding(id)
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+    }
+
+    /// Returns the `k` points closest to the segment from `a` to `b`, nearest first, each with
+    /// its squared distance to the segment. Fewer than `k` points are returned if the tree is
+    /// smaller.
+    pub fn nearest_to_segment(&self, a: &P, b: &P, k: usize) -> Vec<(P::PScalar, &P)> {
+        self.map
+            .nearest_to_segment(a, b, k)
+            .into_iter()
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+            .collect()
+    }
+
+    /// Returns the point closest to `origin` inside the cone around `direction` together with
+    /// its squared distance to `origin`, see [`KDMap::nearest_in_cone`].
+    pub fn nearest_in_cone(
+        &self,
+        origin: &P,
+        direction: &P,
+        half_angle: P::PScalar,
+    ) -> Option<(P::PScalar, &P)> {
+        self.map
+            .nearest_in_cone(origin, direction, half_angle)
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+    }
+
+    /// Returns the point closest to `origin` with a positive dot product with `direction`
+    /// relative to `origin`, together with its squared distance to `origin`.
+    pub fn nearest_ahead(&self, origin: &P, direction: &P) -> Option<(P::PScalar, &P)> {
+        self.map
+            .nearest_ahead(origin, direction)
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+    }
+
+    /// Returns the point within `tolerance` of the ray from `origin` along `dir` that the ray
+    /// reaches first together with its parameter `t`, see [`KDMap::ray_cast`].
+    pub fn ray_cast(&self, origin: &P, dir: &P, tolerance: P::PScalar) -> Option<(P::PScalar, &P)> {
+        self.map
+            .ray_cast(origin, dir, tolerance)
+            .map(|(t, pos, _)| (t, pos))
+    }
+
+    /// Returns the point farthest from `pos` together with its squared distance to `pos`, or
+    /// `None` if the tree is empty.
+    pub fn farthest<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+    ) -> Option<(P::PScalar, &P)> {
+        self.map
+            .farthest(pos)
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+    }
+
+    /// Returns the `k` points farthest from `pos`, farthest first, each with its squared
+    /// distance to `pos`. Fewer than `k` points are returned if the tree is smaller.
+    pub fn farthest_k<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        k: usize,
+    ) -> Vec<(P::PScalar, &P)> {
+        self.map
+            .farthest_k(pos, k)
+            .into_iter()
+            .map(|(dist_sq, pos, _)| (dist_sq, pos))
+            .collect()
+    }
+
+    /// Returns the number of points within `radius` of `pos`, see [`KDMap::count_within`].
+    pub fn count_within<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+    ) -> usize {
+        self.map.count_within(pos, radius)
+    }
+
+    /// Returns `true` if there is at least one point within `radius` of `pos`.
+    pub fn any_within<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+    ) -> bool {
+        self.map.any_within(pos, radius)
+    }
+
+    #[allow(dead_code)]
+    pub fn range_query<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+    ) -> Vec<P> {
+        let mut results: Vec<P> = Vec::new();
+        self.closure_range_query(pos, radius, |site| results.push(site.clone()));
+        results
+    }
+
+    /// Returns all the points inside the axis aligned box spanned by `min` and `max`, edges
+    /// included.
+    pub fn rect_query(&self, min: &P, max: &P) -> Vec<P> {
+        let mut results = Vec::new();
+        self.closure_rect_query(min, max, |pos| results.push(pos.clone()));
+        results
+    }
+
+    /// Calls `process` with every point inside the axis aligned box spanned by `min` and `max`,
+    /// edges included.
+    pub fn closure_rect_query<F>(&self, min: &P, max: &P, mut process: F)
+    where
+        F: FnMut(&P),
+    {
+        self.map.closure_rect_query(min, max, |pos, _| process(pos));
+    }
+
+    /// Returns all the points inside `region`.
+    pub fn region_query<R: Region<P> + ?Sized>(&self, region: &R) -> Vec<P> {
+        let mut results = Vec::new();
+        self.closure_region_query(region, |pos| results.push(pos.clone()));
+        results
+    }
+
+    /// Calls `process` with every point inside `region`.
+    pub fn closure_region_query<R: Region<P> + ?Sized, F>(&self, region: &R, mut process: F)
+    where
+        F: FnMut(&P),
+    {
+        self.map.closure_region_query(region, |pos, _| process(pos));
+    }
+
+    /// Calls `process` once with every point within `radius` of the segment from `a` to `b`.
+    pub fn segment_range_query<F>(&self, a: &P, b: &P, radius: P::PScalar, mut process: F)
+    where
+        F: FnMut(&P),
+    {
+        self.map
+            .segment_range_query(a, b, radius, |pos, _| process(pos));
+    }
+
+    /// Calls `process` once with every point within `radius` of the polyline through `path`.
+    pub fn polyline_range_query<F>(&self, path: &[P], radius: P::PScalar, mut process: F)
+    where
+        F: FnMut(&P),
+    {
+        self.map
+            .polyline_range_query(path, radius, |pos, _| process(pos));
+    }
+
+    /// Returns all the points within `radius` of `pos`, each with its squared distance to
+    /// `pos`, in no particular order.
+    pub fn range_query_ref<Q: PointTrait<PScalar = P::PScalar>>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+    ) -> Vec<(P::PScalar, &P)> {
+        let mut results = Vec::new();
+        self.map
+            .closure_range_query_indices(pos, radius, |index, dist_sq| {
+                results.push((dist_sq, &self.map.nodes[index].pos))
+            });
+        results
+    }
+
+    pub fn closure_range_query<Q: PointTrait<PScalar = P::PScalar>, F>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+        mut process: F,
+    ) where
+        F: FnMut(&P),
+    {
+        self.map
+            .closure_range_query(pos, radius, |site, _| process(site));
+    }
+
+    /// Calls `process` with every point within `radius` of `pos` until it returns
+    /// [`ControlFlow::Break`], see [`KDMap::try_closure_range_query`].
+    pub fn try_closure_range_query<Q: PointTrait<PScalar = P::PScalar>, B, F>(
+        &self,
+        pos: &Q,
+        radius: P::PScalar,
+        mut process: F,
+    ) -> ControlFlow<B>
+    where
+        F: FnMut(&P) -> ControlFlow<B>,
+    {
+        self.map
+            .try_closure_range_query(pos, radius, |site, _| process(site))
+    }
+}
@@ -0,0 +1,420 @@
+/*  SPDX-License-Identifier:LGPL-2.0-only
+ *  Rust code Copyright (c) 2023 lacklustr@protonmail.com https://github.com/eadf
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU Lesser General Public License as published by
+ *  the Free Software Foundation, either version 2.1 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU Lesser General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Lesser General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Shapes that can be used with [`KDMap::region_query`] and [`KDTree::region_query`].
+
+use super::*;
+
+/// A part of the plane that the points of a tree can be queried for.
+pub trait Region<P: PointTrait> {
+    /// Returns `true` if the region may contain points inside `rect`.
+    ///
+    /// Subtrees are only searched if this returns `true` for their cell, so it must never
+    /// return `false` for a rectangle the region overlaps. Returning `true` for a rectangle it
+    /// does not overlap is allowed, it only makes the query visit more nodes.
+    fn intersects(&self, rect: &HyperRectangle<P>) -> bool;
+
+    /// Returns `true` if `pos` is inside the region.
+    fn contains(&self, pos: &P) -> bool;
+}
+
+impl<P: PointTrait> Region<P> for HyperRectangle<P> {
+    fn intersects(&self, rect: &HyperRectangle<P>) -> bool {
+        HyperRectangle::intersects(self, rect)
+    }
+
+    fn contains(&self, pos: &P) -> bool {
+        HyperRectangle::contains(self, pos)
+    }
+}
+
+/// The ring between two circles around the same center, both circles included.
+#[derive(Clone)]
+pub struct Annulus<P: PointTrait> {
+    center: P,
+    inner_radius_sq: P::PScalar,
+    outer_radius_sq: P::PScalar,
+}
+
+impl<P: PointTrait> Annulus<P> {
+    /// Creates the ring around `center`, `inner_radius` must be in the range
+    /// [0, `outer_radius`].
+    pub fn new(
+        center: P,
+        inner_radius: P::PScalar,
+        outer_radius: P::PScalar,
+    ) -> Result<Self, KrakelError> {
+        if !(P::PScalar::zero() <= inner_radius && inner_radius <= outer_radius) {
+            return Err(KrakelError::InvalidParameter(format!(
+                "the radii must satisfy 0 <= inner_radius <= outer_radius, got {} and {}",
+                inner_radius, outer_radius
+            )));
+        }
+        Ok(Self {
+            center,
+            inner_radius_sq: inner_radius * inner_radius,
+            outer_radius_sq: outer_radius * outer_radius,
+        })
+    }
+}
+
+impl<P: PointTrait> Region<P> for Annulus<P> {
+    fn intersects(&self, rect: &HyperRectangle<P>) -> bool {
+        KDMap::<P, ()>::hyper_rect_dist_sq(rect, &self.center) <= self.outer_radius_sq
+            && KDMap::<P, ()>::hyper_rect_max_dist_sq(rect, &self.center) >= self.inner_radius_sq
+    }
+
+    fn contains(&self, pos: &P) -> bool {
+        let dist_sq = P::dist_sq(pos, &self.center);
+        self.inner_radius_sq <= dist_sq && dist_sq <= self.outer_radius_sq
+    }
+}
+
+/// The half of the plane on the side of a line that `normal` points to, the line included.
+#[derive(Clone)]
+pub struct HalfPlane<P: PointTrait> {
+    point: P,
+    normal: P,
+}
+
+impl<P: PointTrait> HalfPlane<P> {
+    /// Creates the half-plane bounded by the line through `point` perpendicular to `normal`.
+    /// `normal` does not need to be normalized.
+    pub fn new(point: P, normal: P) -> Self {
+        Self { point, normal }
+    }
+
+    /// Returns the signed distance of `pos` from the line, scaled by the length of `normal`.
+    fn offset(&self, x: P::PScalar, y: P::PScalar) -> P::PScalar {
+        (x - self.point.x()) * self.normal.x() + (y - self.point.y()) * self.normal.y()
+    }
+}
+
+impl<P: PointTrait> Region<P> for HalfPlane<P> {
+    fn intersects(&self, rect: &HyperRectangle<P>) -> bool {
+        // the corner of the rectangle farthest along the normal
+        let x = if self.normal.x() >= P::PScalar::zero() {
+            rect.max.x()
+        } else {
+            rect.min.x()
+        };
+        let y = if self.normal.y() >= P::PScalar::zero() {
+            rect.max.y()
+        } else {
+            rect.min.y()
+        };
+        self.offset(x, y) >= P::PScalar::zero()
+    }
+
+    fn contains(&self, pos: &P) -> bool {
+        self.offset(pos.x(), pos.y()) >= P::PScalar::zero()
+    }
+}
+
+/// A simple polygon, i.e. one whose edges do not cross each other. It may be concave.
+///
+/// Points exactly on an edge may or may not be considered inside.
+#[derive(Clone)]
+pub struct Polygon<P: PointTrait> {
+    vertices: Vec<P>,
+    bounds: HyperRectangle<P>,
+}
+
+impl<P: PointTrait> Polygon<P> {
+    /// Creates the polygon from at least three `vertices`, in either winding order. The last
+    /// vertex connects back to the first.
+    pub fn new(vertices: Vec<P>) -> Result<Self, KrakelError> {
+        if vertices.len() < 3 {
+            return Err(KrakelError::InvalidParameter(format!(
+                "a polygon needs at least 3 vertices, got {}",
+                vertices.len()
+            )));
+        }
+        let bounds = HyperRectangle::from_points(vertices.iter()).unwrap();
+        Ok(Self { vertices, bounds })
+    }
+
+    /// Returns the edges of the polygon as pairs of vertices.
+    fn edges(&self) -> impl Iterator<Item = (&P, &P)> {
+        let previous = self.vertices.iter().cycle().skip(self.vertices.len() - 1);
+        previous.zip(self.vertices.iter())
+    }
+}
+
+impl<P: PointTrait> Region<P> for Polygon<P> {
+    fn intersects(&self, rect: &HyperRectangle<P>) -> bool {
+        if !self.bounds.intersects(rect) {
+            return false;
+        }
+        // either an edge passes through the rectangle, or one of them lies entirely inside the
+        // other, which the corner test below covers when the rectangle is the inner one
+        self.edges()
+            .any(|(a, b)| segment_intersects_rect(a, b, rect))
+            || self.contains(&rect.min)
+    }
+
+    /// Even-odd test, counting the edges crossed by a ray from `pos` towards +x.
+    fn contains(&self, pos: &P) -> bool {
+        let mut inside = false;
+        for (a, b) in self.edges() {
+            if (a.y() > pos.y()) != (b.y() > pos.y()) {
+                let x = a.x() + (pos.y() - a.y()) * (b.x() - a.x()) / (b.y() - a.y());
+                if pos.x() < x {
+                    inside = !inside;
+                }
+            }
+        }
+        inside
+    }
+}
+
+/// All the points within a distance of a path of line segments, i.e. the area swept by a circle
+/// moving along the path. A single segment gives a capsule (stadium) shape.
+#[derive(Clone)]
+pub struct Capsule<P: PointTrait> {
+    path: Vec<P>,
+    radius_sq: P::PScalar,
+    /// The bounds of `path` grown by the radius
+    bounds: Option<HyperRectangle<P>>,
+}
+
+impl<P: PointTrait> Capsule<P> {
+    /// Creates the region of all the points within `radius` of the segment from `a` to `b`.
+    pub fn new(a: P, b: P, radius: P::PScalar) -> Self {
+        Self::from_path(vec![a, b], radius)
+    }
+
+    /// Creates the region of all the points within `radius` of the polyline through `path`.
+    /// A path with a single vertex gives a circle, an empty one gives an empty region.
+    pub fn from_path(path: Vec<P>, radius: P::PScalar) -> Self {
+        let bounds = HyperRectangle::from_points(path.iter()).map(|mut bounds| {
+            for i in 0..P::DIMENSION {
+                *bounds.min.at_mut(i) -= radius;
+                *bounds.max.at_mut(i) += radius;
+            }
+            bounds
+        });
+        Self {
+            path,
+            radius_sq: radius * radius,
+            bounds,
+        }
+    }
+
+    /// Returns the segments of the path, a single vertex counts as a segment of zero length.
+    fn segments(&self) -> impl Iterator<Item = (&P, &P)> {
+        let single = (self.path.len() == 1).then(|| (&self.path[0], &self.path[0]));
+        self.path
+            .windows(2)
+            .map(|pair| (&pair[0], &pair[1]))
+            .chain(single)
+    }
+}
+
+impl<P: PointTrait> Region<P> for Capsule<P> {
+    fn intersects(&self, rect: &HyperRectangle<P>) -> bool {
+        self.bounds
+            .as_ref()
+            .is_some_and(|bounds| bounds.intersects(rect))
+            && self
+                .segments()
+                .any(|(a, b)| segment_rect_dist_sq(a, b, rect) <= self.radius_sq)
+    }
+
+    fn contains(&self, pos: &P) -> bool {
+        self.segments()
+            .any(|(a, b)| segment_dist_sq(a, b, pos) <= self.radius_sq)
+    }
+}
+
+/// The points seen from `apex` at an angle of less than `half_angle` from `direction`. The apex
+/// itself is not included.
+///
+/// A `half_angle` of π/2 gives the open half-plane ahead of the apex, larger angles give all
+/// but a cone behind it.
+#[derive(Clone)]
+pub struct Cone<P: PointTrait> {
+    apex: P,
+    direction: P,
+    cos: P::PScalar,
+    sin: P::PScalar,
+}
+
+impl<P: PointTrait> Cone<P> {
+    /// Creates the cone around `direction`, which does not need to be normalized. `half_angle`
+    /// is in radians and clamped to [0, π], a zero `direction` gives an empty cone.
+    pub fn new(apex: P, direction: P, half_angle: P::PScalar) -> Self {
+        let pi = P::PScalar::from_f64(std::f64::consts::PI).unwrap();
+        let half_angle = half_angle.max(P::PScalar::zero()).min(pi);
+        Self {
+            apex,
+            direction,
+            cos: half_angle.cos(),
+            sin: half_angle.sin(),
+        }
+    }
+
+    /// Creates the open half-plane of the points with a positive dot product with `direction`,
+    /// relative to `apex`. Same as a cone with a `half_angle` of π/2, without its rounding.
+    pub fn ahead(apex: P, direction: P) -> Self {
+        Self {
+            apex,
+            direction,
+            cos: P::PScalar::zero(),
+            sin: P::PScalar::one(),
+        }
+    }
+
+    /// Returns `true` if the direction `(x, y)` from the apex is inside the cone.
+    fn contains_direction(&self, x: P::PScalar, y: P::PScalar) -> bool {
+        let zero = P::PScalar::zero();
+        let dot = x * self.direction.x() + y * self.direction.y();
+        // compares the squares of dot and |v||d|cos, which saves the square roots
+        let bound_sq = self.cos
+            * self.cos
+            * (x * x + y * y)
+            * (self.direction.x() * self.direction.x() + self.direction.y() * self.direction.y());
+        if self.cos >= zero {
+            dot > zero && dot * dot > bound_sq
+        } else {
+            dot > zero || dot * dot < bound_sq
+        }
+    }
+}
+
+impl<P: PointTrait> Region<P> for Cone<P> {
+    /// A rectangle overlaps the cone if one of its corners is inside it, or else if one of the
+    /// two rays bounding the cone crosses it.
+    fn intersects(&self, rect: &HyperRectangle<P>) -> bool {
+        let (ax, ay) = (self.apex.x(), self.apex.y());
+        let corner_inside = [
+            (rect.min.x(), rect.min.y()),
+            (rect.max.x(), rect.min.y()),
+            (rect.min.x(), rect.max.y()),
+            (rect.max.x(), rect.max.y()),
+        ]
+        .into_iter()
+        .any(|(x, y)| self.contains_direction(x - ax, y - ay));
+        if corner_inside {
+            return true;
+        }
+        let (dx, dy) = (self.direction.x(), self.direction.y());
+        if dx == P::PScalar::zero() && dy == P::PScalar::zero() {
+            return false;
+        }
+        [self.sin, -self.sin].into_iter().any(|sin| {
+            let (x, y) = (dx * self.cos - dy * sin, dx * sin + dy * self.cos);
+            clip_to_rect(&self.apex, x, y, P::PScalar::max_value(), rect).is_some()
+        })
+    }
+
+    fn contains(&self, pos: &P) -> bool {
+        self.contains_direction(pos.x() - self.apex.x(), pos.y() - self.apex.y())
+    }
+}
+
+/// Returns the squared distance from `pos` to the segment from `a` to `b`.
+pub(crate) fn segment_dist_sq<P: PointTrait>(a: &P, b: &P, pos: &P) -> P::PScalar {
+    let (dx, dy) = (b.x() - a.x(), b.y() - a.y());
+    let length_sq = dx * dx + dy * dy;
+    let zero = P::PScalar::zero();
+    let t = if length_sq > zero {
+        let t = ((pos.x() - a.x()) * dx + (pos.y() - a.y()) * dy) / length_sq;
+        t.max(zero).min(P::PScalar::one())
+    } else {
+        zero
+    };
+    let (x, y) = (a.x() + t * dx - pos.x(), a.y() + t * dy - pos.y());
+    x * x + y * y
+}
+
+/// Returns the squared distance between the segment from `a` to `b` and `rect`, zero if they
+/// touch.
+pub(crate) fn segment_rect_dist_sq<P: PointTrait>(
+    a: &P,
+    b: &P,
+    rect: &HyperRectangle<P>,
+) -> P::PScalar {
+    if segment_intersects_rect(a, b, rect) {
+        return P::PScalar::zero();
+    }
+    // the closest pair is made up of an end point of the segment or a corner of the rectangle
+    let mut corner = rect.min.clone();
+    let mut result = KDMap::<P, ()>::hyper_rect_dist_sq(rect, a)
+        .min(KDMap::<P, ()>::hyper_rect_dist_sq(rect, b));
+    for (x, y) in [
+        (rect.min.x(), rect.min.y()),
+        (rect.max.x(), rect.min.y()),
+        (rect.min.x(), rect.max.y()),
+        (rect.max.x(), rect.max.y()),
+    ] {
+        corner.set_x(x);
+        corner.set_y(y);
+        result = result.min(segment_dist_sq(a, b, &corner));
+    }
+    result
+}
+
+/// Returns `true` if the segment from `a` to `b` touches `rect`.
+pub(crate) fn segment_intersects_rect<P: PointTrait>(
+    a: &P,
+    b: &P,
+    rect: &HyperRectangle<P>,
+) -> bool {
+    clip_to_rect(a, b.x() - a.x(), b.y() - a.y(), P::PScalar::one(), rect).is_some()
+}
+
+/// Clips the line `a + t * (dx, dy)`, `t` in [0, `t_max`], against the four sides of `rect`
+/// (Liang–Barsky). Returns the range of `t` inside the rectangle, or `None` if the line misses
+/// it.
+pub(crate) fn clip_to_rect<P: PointTrait>(
+    a: &P,
+    dx: P::PScalar,
+    dy: P::PScalar,
+    t_max: P::PScalar,
+    rect: &HyperRectangle<P>,
+) -> Option<(P::PScalar, P::PScalar)> {
+    let zero = P::PScalar::zero();
+    let (mut t0, mut t1) = (zero, t_max);
+    for (p, q) in [
+        (-dx, a.x() - rect.min.x()),
+        (dx, rect.max.x() - a.x()),
+        (-dy, a.y() - rect.min.y()),
+        (dy, rect.max.y() - a.y()),
+    ] {
+        if p == zero {
+            // parallel to this side, and entirely outside of it
+            if q < zero {
+                return None;
+            }
+        } else {
+            let t = q / p;
+            if p < zero {
+                if t > t1 {
+                    return None;
+                }
+                t0 = t0.max(t);
+            } else {
+                if t < t0 {
+                    return None;
+                }
+                t1 = t1.min(t);
+            }
+        }
+    }
+    Some((t0, t1))
+}
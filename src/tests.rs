@@ -6,7 +6,10 @@ compile_error!(
 
 #[cfg(all(feature = "glam", feature = "cgmath", feature = "vector-traits"))]
 mod tests {
-    use super::super::{KDTree, PointTrait};
+    use super::super::{
+        region::{Annulus, Cone, HalfPlane, Polygon},
+        HyperRectangle, KDMap, KDTree, PointId, PointTrait, QueryStats, Region,
+    };
     use approx::{AbsDiffEq, UlpsEq};
     use std::cmp::Reverse;
     use vector_traits::GenericVector2;
@@ -680,4 +683,1395 @@ mod tests {
     fn test_at_mut_vec2a() {
         Vec2A::new(1.0, 2.0).at_mut(3);
     }
+
+    #[test]
+    fn test_from_points() {
+        use crate::PointTrait;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        // A sorted grid, the worst case for incremental insertion
+        let mut points = Vec::new();
+        for x in 0..64 {
+            for y in 0..64 {
+                points.push(glam::DVec2::new(x as f64 * 0.15, y as f64 * 0.15));
+            }
+        }
+
+        let mut incremental = KDTree::<glam::DVec2>::default();
+        for point in &points {
+            incremental.insert(*point).unwrap();
+        }
+        let balanced = KDTree::from_points(points.clone());
+
+        // a perfectly balanced tree of 4096 nodes has a depth of 13
+        assert_eq!(balanced.depth(), 13);
+        assert!(incremental.depth() > balanced.depth());
+
+        for _ in 0..300 {
+            let search_point = glam::DVec2 {
+                x: rng.gen_range(-1.0..11.0),
+                y: rng.gen_range(-1.0..11.0),
+            };
+            let a = incremental.nearest(&search_point).unwrap();
+            let b = balanced.nearest(&search_point).unwrap();
+            assert_eq!(
+                PointTrait::dist_sq(&search_point, &a),
+                PointTrait::dist_sq(&search_point, &b)
+            );
+
+            let mut a = incremental.range_query(&search_point, 0.5);
+            let mut b = balanced.range_query(&search_point, 0.5);
+            a.sort_by(|p, q| p.x.total_cmp(&q.x).then(p.y.total_cmp(&q.y)));
+            b.sort_by(|p, q| p.x.total_cmp(&q.x).then(p.y.total_cmp(&q.y)));
+            assert_eq!(a, b);
+        }
+    }
+
+    #[test]
+    fn test_from_points_empty() {
+        let kdtree = KDTree::<glam::DVec2>::from_points(Vec::new());
+        assert_eq!(kdtree.depth(), 0);
+        assert!(kdtree.nearest(&glam::DVec2::new(1.0, 1.0)).is_none());
+        assert!(kdtree
+            .range_query(&glam::DVec2::new(1.0, 1.0), 1.0)
+            .is_empty());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_from_points() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        fn assert_send<T: Send>() {}
+        assert_send::<KDTree<glam::DVec2>>();
+        assert_send::<crate::KDNode<glam::DVec2, ()>>();
+
+        let points: Vec<_> = (0..50_000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+
+        let sequential = KDTree::from_points(points.clone());
+        let parallel = KDTree::par_from_points(points);
+        // Both builders split identically, so the trees must have the same shape
+        assert_eq!(format!("{:?}", sequential), format!("{:?}", parallel));
+
+        let search_point = glam::DVec2 { x: 7.5, y: 3.5 };
+        assert_eq!(
+            sequential.nearest(&search_point),
+            parallel.nearest(&search_point)
+        );
+        assert_eq!(
+            sequential.range_query(&search_point, 0.2),
+            parallel.range_query(&search_point, 0.2)
+        );
+    }
+
+    #[test]
+    fn test_remove() {
+        use crate::PointTrait;
+        use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let mut points: Vec<_> = (0..1000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        // add some duplicates
+        points.extend_from_within(0..100);
+
+        let mut kdtree = KDTree::from_points(points.clone());
+        assert!(kdtree.remove(&glam::DVec2::new(-1.0, -1.0)).is_none());
+
+        points.shuffle(&mut rng);
+        while let Some(point) = points.pop() {
+            assert_eq!(kdtree.remove(&point), Some(point));
+
+            if points.len() % 50 == 0 {
+                let search_point = glam::DVec2 {
+                    x: rng.gen_range(0.0..10.0),
+                    y: rng.gen_range(0.0..10.0),
+                };
+                let expected = points
+                    .iter()
+                    .map(|p| PointTrait::dist_sq(&search_point, p))
+                    .min_by(|a, b| a.total_cmp(b));
+                let found = kdtree
+                    .nearest(&search_point)
+                    .map(|p| PointTrait::dist_sq(&search_point, &p));
+                assert_eq!(expected, found);
+                assert_eq!(kdtree.range_query(&search_point, 0.5).len(), {
+                    points
+                        .iter()
+                        .filter(|p| PointTrait::dist_sq(&search_point, *p) <= 0.25)
+                        .count()
+                });
+            }
+        }
+        assert_eq!(kdtree.depth(), 0);
+        assert!(kdtree.nearest(&glam::DVec2::new(1.0, 1.0)).is_none());
+
+        // the tree is still usable after being emptied
+        kdtree.insert(glam::DVec2::new(1.0, 1.0)).unwrap();
+        assert_eq!(
+            kdtree.nearest(&glam::DVec2::new(5.0, 5.0)),
+            Some(glam::DVec2::new(1.0, 1.0))
+        );
+    }
+
+    #[test]
+    fn test_remove_nearest() {
+        use crate::PointTrait;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let mut points: Vec<_> = (0..500)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let mut kdtree = KDTree::<glam::DVec2>::default();
+        for point in &points {
+            kdtree.insert(*point).unwrap();
+        }
+
+        // popping the nearest point repeatedly must yield the points sorted by distance
+        let search_point = glam::DVec2 { x: 7.5, y: 3.5 };
+        points.sort_by(|a, b| {
+            PointTrait::dist_sq(&search_point, a).total_cmp(&PointTrait::dist_sq(&search_point, b))
+        });
+        for expected in &points {
+            let found = kdtree.remove_nearest(&search_point).unwrap();
+            assert_eq!(
+                PointTrait::dist_sq(&search_point, expected),
+                PointTrait::dist_sq(&search_point, &found)
+            );
+        }
+        assert!(kdtree.remove_nearest(&search_point).is_none());
+    }
+
+    #[test]
+    fn test_balanced_insert() {
+        use crate::PointTrait;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        assert!(KDTree::<glam::DVec2>::with_balancing(0.5).is_err());
+        assert!(KDTree::<glam::DVec2>::with_balancing(1.0).is_err());
+
+        let alpha = 0.7;
+        let mut kdtree = KDTree::<glam::DVec2>::with_balancing(alpha).unwrap();
+        let mut unbalanced = KDTree::<glam::DVec2>::default();
+
+        // points inserted in scan order
+        let mut points = Vec::new();
+        for x in 0..100 {
+            for y in 0..100 {
+                let point = glam::DVec2::new(x as f64 * 0.1, y as f64 * 0.1);
+                points.push(point);
+                kdtree.insert(point).unwrap();
+                unbalanced.insert(point).unwrap();
+
+                let stats = kdtree.stats();
+                assert_eq!(stats.len, points.len());
+                // depth is counted in nodes, not edges
+                let max_depth = (stats.len as f64).log(1.0 / alpha) as usize + 1;
+                assert!(
+                    stats.depth <= max_depth,
+                    "depth {} exceeds {} at len {}",
+                    stats.depth,
+                    max_depth,
+                    stats.len
+                );
+            }
+        }
+        let stats = kdtree.stats();
+        assert!(stats.rebuilds > 0);
+        assert_eq!(unbalanced.stats().rebuilds, 0);
+        assert!(unbalanced.depth() > 5 * stats.depth);
+
+        for _ in 0..300 {
+            let search_point = glam::DVec2 {
+                x: rng.gen_range(-1.0..11.0),
+                y: rng.gen_range(-1.0..11.0),
+            };
+            let expected = points
+                .iter()
+                .map(|p| PointTrait::dist_sq(&search_point, p))
+                .min_by(|a, b| a.total_cmp(b));
+            let found = kdtree
+                .nearest(&search_point)
+                .map(|p| PointTrait::dist_sq(&search_point, &p));
+            assert_eq!(expected, found);
+            assert_eq!(
+                kdtree.range_query(&search_point, 0.35).len(),
+                unbalanced.range_query(&search_point, 0.35).len()
+            );
+        }
+
+        // sizes must stay consistent with removals
+        for point in &points[..5000] {
+            assert_eq!(kdtree.remove(point), Some(*point));
+        }
+        assert_eq!(kdtree.len(), 5000);
+        kdtree.insert(points[0]).unwrap();
+        assert_eq!(kdtree.len(), 5001);
+    }
+
+    #[test]
+    fn test_deep_tree_small_stack() {
+        use crate::{HyperRectangle, KDMap, KDNode};
+        const N: usize = 1_000_000;
+
+        fn point(i: usize) -> glam::DVec2 {
+            glam::DVec2::new(i as f64, i as f64)
+        }
+
+        // Inserting monotonically increasing points turns the tree into a chain where every node
+        // is the right child of the previous one. Inserting them one at a time takes O(n²), so the
+        // chain is linked up directly instead.
+        fn chain(n: usize) -> KDTree<glam::DVec2> {
+            let nodes = (0..n)
+                .map(|i| KDNode {
+                    pos: point(i),
+                    value: (),
+                    id: i,
+                    dir: (i % 2) as u8,
+                    size: n - i,
+                    left: None,
+                    right: (i + 1 < n).then_some(i + 1),
+                })
+                .collect();
+            KDTree {
+                map: KDMap {
+                    nodes,
+                    root: Some(0),
+                    slots: (0..n).map(Some).collect(),
+                    rect: Some(HyperRectangle {
+                        min: point(0),
+                        max: point(n - 1),
+                    }),
+                    ..Default::default()
+                },
+            }
+        }
+        let mut inserted = KDTree::default();
+        for i in 0..100 {
+            inserted.insert(point(i)).unwrap();
+        }
+        assert_eq!(format!("{:?}", inserted), format!("{:?}", chain(100)));
+
+        let kdtree = chain(N);
+        std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(move || {
+                let mut kdtree = kdtree;
+                kdtree.insert(point(N)).unwrap();
+                assert_eq!(kdtree.depth(), N + 1);
+
+                let search_point = glam::DVec2::new(N as f64 / 2.0 + 0.4, N as f64 / 2.0);
+                assert_eq!(kdtree.nearest(&search_point), Some(point(N / 2)));
+                let expected = |radius: f64| {
+                    (0..=N)
+                        .filter(|i| {
+                            PointTrait::dist_sq(&search_point, &point(*i)) <= radius * radius
+                        })
+                        .count()
+                };
+                assert_eq!(kdtree.range_query(&search_point, 2.0).len(), expected(2.0));
+                let mut count = 0;
+                kdtree.closure_range_query(&search_point, 100.0, |_| count += 1);
+                assert_eq!(count, expected(100.0));
+
+                assert_eq!(kdtree.remove_nearest(&point(2 * N)), Some(point(N)));
+                assert_eq!(kdtree.remove(&point(N - 1)), Some(point(N - 1)));
+                assert_eq!(kdtree.stats().depth, N - 1);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_kdmap() {
+        use crate::PointTrait;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let mut points: Vec<_> = (0..1000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        // duplicated positions with different values
+        points.extend_from_within(0..10);
+
+        let mut kdmap = KDMap::<glam::DVec2, usize>::default();
+        for (i, point) in points.iter().enumerate() {
+            kdmap.insert(*point, i).unwrap();
+        }
+        let bulk = KDMap::from_entries(points.iter().copied().zip(0..).collect());
+
+        for (i, point) in points.iter().enumerate().take(1000).skip(10) {
+            assert_eq!(kdmap.nearest(point), Some((point, &i)));
+            assert_eq!(bulk.nearest(point), Some((point, &i)));
+        }
+
+        let search_point = glam::DVec2 { x: 7.5, y: 3.5 };
+        let mut found: Vec<usize> = kdmap
+            .range_query(&search_point, 1.0)
+            .into_iter()
+            .map(|(pos, value)| {
+                assert_eq!(*pos, points[*value]);
+                *value
+            })
+            .collect();
+        found.sort();
+        let expected: Vec<usize> = (0..points.len())
+            .filter(|i| PointTrait::dist_sq(&search_point, &points[*i]) <= 1.0)
+            .collect();
+        assert_eq!(found, expected);
+
+        let mut found = Vec::new();
+        bulk.closure_range_query(&search_point, 1.0, |_, value| found.push(*value));
+        found.sort();
+        assert_eq!(found, expected);
+
+        // removing a duplicated position must hand back both values
+        let mut removed = vec![
+            kdmap.remove(&points[3]).unwrap().1,
+            kdmap.remove(&points[3]).unwrap().1,
+        ];
+        removed.sort();
+        assert_eq!(removed, vec![3, 1003]);
+        assert!(kdmap.remove(&points[3]).is_none());
+
+        for (i, point) in points.iter().enumerate().take(1000).skip(4) {
+            assert_eq!(
+                kdmap.remove_nearest(point).map(|(_, value)| value % 1000),
+                Some(i)
+            );
+        }
+    }
+
+    #[test]
+    fn test_point_ids() {
+        use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..2000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+
+        let bulk = KDTree::from_points(points.clone());
+        for (i, point) in points.iter().enumerate() {
+            let id = bulk.nearest_id(point).unwrap();
+            assert_eq!(id.index(), i);
+            assert_eq!(bulk.get(id), Some(point));
+        }
+
+        // rebuilds and removals move entries between nodes, the handles must follow them
+        let mut kdmap = KDMap::<glam::DVec2, usize>::with_balancing(0.6).unwrap();
+        let mut live: Vec<(PointId, usize)> = points
+            .iter()
+            .enumerate()
+            .map(|(i, point)| (kdmap.insert(*point, i).unwrap(), i))
+            .collect();
+        assert!(kdmap.stats().rebuilds > 0);
+
+        live.shuffle(&mut rng);
+        for _ in 0..300 {
+            let (id, i) = live.pop().unwrap();
+            assert_eq!(kdmap.remove_by_id(id), Some((points[i], i)));
+            assert!(kdmap.get(id).is_none());
+            assert!(kdmap.remove_by_id(id).is_none());
+
+            let (point, value) = kdmap.remove_nearest(&points[live[0].1]).unwrap();
+            assert_eq!(point, points[value]);
+            live.retain(|(_, i)| *i != value);
+        }
+        for &(id, i) in &live {
+            assert_eq!(kdmap.get(id), Some((&points[i], &i)));
+        }
+
+        let search_point = glam::DVec2 { x: 7.5, y: 3.5 };
+        let mut found = kdmap.range_query_ids(&search_point, 1.0);
+        found.sort();
+        let mut expected: Vec<PointId> = kdmap
+            .range_query(&search_point, 1.0)
+            .into_iter()
+            .map(|(_, i)| live.iter().find(|(_, j)| i == j).unwrap().0)
+            .collect();
+        expected.sort();
+        assert_eq!(found, expected);
+
+        // handles of removed entries are reused
+        let id = kdmap.insert(search_point, usize::MAX).unwrap();
+        assert!(id.index() < points.len());
+        assert_eq!(kdmap.get(id), Some((&search_point, &usize::MAX)));
+        assert_eq!(kdmap.nearest_id(&search_point), Some(id));
+    }
+
+    #[test]
+    fn test_update() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let mut points: Vec<_> = (0..1000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let mut kdtree = KDTree::from_points(points.clone());
+
+        // small steps mostly stay in place, some cross a split and get reinserted
+        for _ in 0..20 {
+            for (i, point) in points.iter_mut().enumerate() {
+                let moved = glam::DVec2 {
+                    x: point.x + rng.gen_range(-0.1..0.1),
+                    y: point.y + rng.gen_range(-0.1..0.1),
+                };
+                assert_eq!(kdtree.update_by_id(PointId(i), moved), Some(*point));
+                *point = moved;
+            }
+        }
+        for (i, point) in points.iter().enumerate() {
+            assert_eq!(kdtree.get(PointId(i)), Some(point));
+        }
+        for _ in 0..200 {
+            let search_point = glam::DVec2 {
+                x: rng.gen_range(-1.0..11.0),
+                y: rng.gen_range(-1.0..11.0),
+            };
+            let expected = points
+                .iter()
+                .map(|p| PointTrait::dist_sq(p, &search_point))
+                .fold(f64::INFINITY, f64::min);
+            let found = kdtree.nearest(&search_point).unwrap();
+            assert_eq!(PointTrait::dist_sq(&found, &search_point), expected);
+        }
+
+        // moving a point far outside of the original bounds
+        let far = glam::DVec2 { x: 100.0, y: -50.0 };
+        assert_eq!(kdtree.update(&points[7], far), Some(PointId(7)));
+        assert_eq!(
+            kdtree.nearest(&glam::DVec2 { x: 90.0, y: -40.0 }),
+            Some(far)
+        );
+        assert_eq!(kdtree.len(), points.len());
+
+        assert!(kdtree
+            .update(&glam::DVec2 { x: -5.0, y: -5.0 }, far)
+            .is_none());
+        assert!(kdtree.remove_by_id(PointId(3)).is_some());
+        assert!(kdtree.update_by_id(PointId(3), far).is_none());
+    }
+
+    #[test]
+    fn test_bulk_removal() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..4000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let center = glam::DVec2 { x: 3.0, y: 4.0 };
+        let mut kdtree = KDTree::from_points(points.clone());
+
+        let mut removed = kdtree.remove_within(&center, 2.0);
+        let mut expected: Vec<_> = points
+            .iter()
+            .filter(|p| PointTrait::dist_sq(*p, &center) <= 4.0)
+            .copied()
+            .collect();
+        let key = |p: &glam::DVec2| (p.x, p.y);
+        removed.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        expected.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        assert_eq!(removed, expected);
+        assert_eq!(kdtree.len(), points.len() - expected.len());
+        assert!(kdtree.range_query(&center, 2.0).is_empty());
+
+        // the survivors keep their handles
+        let rebuilds = kdtree.stats().rebuilds;
+        kdtree.retain(|p| p.x < 5.0);
+        assert_eq!(kdtree.stats().rebuilds, rebuilds + 1);
+        for (i, point) in points.iter().enumerate() {
+            let alive = point.x < 5.0 && PointTrait::dist_sq(point, &center) > 4.0;
+            assert_eq!(kdtree.get(PointId(i)), alive.then_some(point));
+        }
+
+        // removing most of the remaining points one at a time rebuilds the tree
+        let mut kdtree = KDTree::from_points(points.clone());
+        for i in 100..points.len() {
+            assert!(kdtree.remove_by_id(PointId(i)).is_some());
+        }
+        assert!(kdtree.stats().rebuilds > 0);
+        assert!(kdtree.depth() <= 8);
+        for point in &points[..100] {
+            assert_eq!(kdtree.nearest(point), Some(*point));
+        }
+
+        let mut drained = kdtree.drain();
+        drained.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        let mut expected = points[..100].to_vec();
+        expected.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        assert_eq!(drained, expected);
+        assert!(kdtree.is_empty());
+        assert!(kdtree.nearest(&center).is_none());
+
+        let mut kdmap = KDMap::from_entries(points.iter().map(|p| (*p, 1)).collect());
+        kdmap.clear();
+        assert!(kdmap.is_empty());
+        assert_eq!(kdmap.insert(center, 2).unwrap(), PointId(0));
+        assert_eq!(kdmap.nearest(&points[0]), Some((&center, &2)));
+    }
+
+    #[test]
+    fn test_query_pruning() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..100_000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..1000.0),
+                y: rng.gen_range(0.0..1000.0),
+            })
+            .collect();
+        let kdtree = KDTree::from_points(points.clone());
+
+        let mut total = QueryStats::default();
+        for _ in 0..100 {
+            let search_point = glam::DVec2 {
+                x: rng.gen_range(0.0..1000.0),
+                y: rng.gen_range(0.0..1000.0),
+            };
+            let (found, stats) = kdtree.nearest_with_stats(&search_point);
+            let expected = points
+                .iter()
+                .map(|p| PointTrait::dist_sq(p, &search_point))
+                .fold(f64::INFINITY, f64::min);
+            assert_eq!(
+                PointTrait::dist_sq(&found.unwrap(), &search_point),
+                expected
+            );
+            assert!(stats.visited < points.len() / 500, "{:?}", stats);
+            total.visited += stats.visited;
+        }
+        // a balanced tree of 100k points is 17 levels deep
+        assert!(total.visited / 100 < 100, "{:?}", total);
+        let empty = KDTree::<glam::DVec2>::default();
+        assert_eq!(
+            empty.nearest_with_stats(&points[0]),
+            (None, QueryStats::default())
+        );
+    }
+
+    #[test]
+    fn test_nearest_k() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let mut points: Vec<_> = (0..2000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        // duplicates must be reported once per stored point
+        points.extend_from_within(..50);
+        let kdtree = KDTree::from_points(points.clone());
+
+        for k in [0, 1, 2, 7, 50, 300] {
+            for _ in 0..20 {
+                let search_point = glam::DVec2 {
+                    x: rng.gen_range(-1.0..11.0),
+                    y: rng.gen_range(-1.0..11.0),
+                };
+                let mut expected: Vec<_> = points
+                    .iter()
+                    .map(|p| PointTrait::dist_sq(p, &search_point))
+                    .collect();
+                expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                expected.truncate(k);
+
+                let found = kdtree.nearest_k(&search_point, k);
+                let distances: Vec<_> = found.iter().map(|(dist_sq, _)| *dist_sq).collect();
+                assert_eq!(distances, expected);
+                for (dist_sq, point) in found {
+                    assert_eq!(PointTrait::dist_sq(point, &search_point), dist_sq);
+                }
+            }
+        }
+
+        let small = KDTree::from_points(points[..5].to_vec());
+        assert_eq!(small.nearest_k(&points[0], 10).len(), 5);
+        assert!(KDTree::<glam::DVec2>::default()
+            .nearest_k(&points[0], 3)
+            .is_empty());
+
+        let kdmap = KDMap::from_entries(points.iter().enumerate().map(|(i, p)| (*p, i)).collect());
+        let found = kdmap.nearest_k(&points[100], 1);
+        assert_eq!(found, vec![(0.0, &points[100], &100)]);
+    }
+
+    #[test]
+    fn test_borrowing_queries() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..1000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let kdtree = KDTree::from_points(points.clone());
+
+        for _ in 0..50 {
+            let search_point = glam::DVec2 {
+                x: rng.gen_range(-1.0..11.0),
+                y: rng.gen_range(-1.0..11.0),
+            };
+            let (dist_sq, nearest) = kdtree.nearest_with_distance(&search_point).unwrap();
+            assert_eq!(PointTrait::dist_sq(nearest, &search_point), dist_sq);
+            assert_eq!(kdtree.nearest_ref(&search_point), Some(nearest));
+            assert_eq!(kdtree.nearest(&search_point).as_ref(), Some(nearest));
+            // any point type with the same scalar can be used for the query
+            let other = cgmath::Vector2::new(search_point.x, search_point.y);
+            assert_eq!(kdtree.nearest_ref(&other), Some(nearest));
+
+            let mut found = kdtree.range_query_ref(&search_point, 1.5);
+            let mut expected: Vec<_> = points
+                .iter()
+                .map(|p| (PointTrait::dist_sq(p, &search_point), p))
+                .filter(|(dist_sq, _)| *dist_sq <= 1.5 * 1.5)
+                .collect();
+            found.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            expected.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            assert_eq!(found, expected);
+        }
+        assert!(KDTree::<glam::DVec2>::default()
+            .nearest_with_distance(&points[0])
+            .is_none());
+    }
+
+    #[test]
+    fn test_nearest_iter() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let mut points: Vec<_> = (0..1000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        points.extend_from_within(..20);
+        let kdtree = KDTree::from_points(points.clone());
+
+        for _ in 0..20 {
+            let search_point = glam::DVec2 {
+                x: rng.gen_range(-1.0..11.0),
+                y: rng.gen_range(-1.0..11.0),
+            };
+            let mut expected: Vec<_> = points
+                .iter()
+                .map(|p| PointTrait::dist_sq(p, &search_point))
+                .collect();
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+            let found: Vec<_> = kdtree.nearest_iter(&search_point).collect();
+            let distances: Vec<_> = found.iter().map(|(dist_sq, _)| *dist_sq).collect();
+            assert_eq!(distances, expected);
+            for (dist_sq, point) in &found {
+                assert_eq!(PointTrait::dist_sq(*point, &search_point), *dist_sq);
+            }
+            assert_eq!(
+                kdtree
+                    .nearest_iter(&search_point)
+                    .take(10)
+                    .collect::<Vec<_>>(),
+                kdtree.nearest_k(&search_point, 10)
+            );
+
+            // the first point far enough from the origin, without knowing k up front
+            let origin = glam::DVec2::ZERO;
+            let found = kdtree
+                .nearest_iter(&search_point)
+                .find(|(_, p)| PointTrait::dist_sq(*p, &origin) > 25.0)
+                .unwrap();
+            let expected = points
+                .iter()
+                .filter(|p| PointTrait::dist_sq(*p, &origin) > 25.0)
+                .map(|p| PointTrait::dist_sq(p, &search_point))
+                .fold(f64::INFINITY, f64::min);
+            assert_eq!(found.0, expected);
+        }
+        assert!(KDTree::<glam::DVec2>::default()
+            .nearest_iter(&points[0])
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn test_rect_query() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        // a coarse grid puts many points exactly on the edges of the boxes
+        let points: Vec<_> = (0..3000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0..100) as f64 / 10.0,
+                y: rng.gen_range(0..100) as f64 / 10.0,
+            })
+            .collect();
+        let kdtree = KDTree::from_points(points.clone());
+        let key = |p: &glam::DVec2| (p.x, p.y);
+
+        for _ in 0..50 {
+            let (x0, x1) = (rng.gen_range(-10..100), rng.gen_range(-10..110));
+            let (y0, y1) = (rng.gen_range(-10..100), rng.gen_range(-10..110));
+            let min = glam::DVec2::new(x0.min(x1) as f64 / 10.0, y0.min(y1) as f64 / 10.0);
+            let max = glam::DVec2::new(x0.max(x1) as f64 / 10.0, y0.max(y1) as f64 / 10.0);
+
+            let mut found = kdtree.rect_query(&min, &max);
+            let mut expected: Vec<_> = points
+                .iter()
+                .filter(|p| min.x <= p.x && p.x <= max.x && min.y <= p.y && p.y <= max.y)
+                .copied()
+                .collect();
+            found.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+            expected.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+            assert_eq!(found, expected);
+
+            let mut count = 0;
+            kdtree.closure_rect_query(&min, &max, |_| count += 1);
+            assert_eq!(count, expected.len());
+        }
+
+        let kdmap = KDMap::from_entries(points.iter().enumerate().map(|(i, p)| (*p, i)).collect());
+        let min = glam::DVec2::new(20.0, 20.0);
+        assert!(kdmap
+            .rect_query(&min, &glam::DVec2::new(30.0, 30.0))
+            .is_empty());
+        assert!(KDTree::<glam::DVec2>::default()
+            .rect_query(&min, &min)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_nearest_approx() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..5000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let kdtree = KDTree::from_points(points.clone());
+
+        for epsilon in [0.0, 0.1, 0.5, 2.0] {
+            let bound = (1.0 + epsilon) * (1.0 + epsilon);
+            for _ in 0..100 {
+                let search_point = glam::DVec2 {
+                    x: rng.gen_range(-1.0..11.0),
+                    y: rng.gen_range(-1.0..11.0),
+                };
+                let mut expected: Vec<_> = points
+                    .iter()
+                    .map(|p| PointTrait::dist_sq(p, &search_point))
+                    .collect();
+                expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+                let (dist_sq, point) = kdtree.nearest_approx(&search_point, epsilon).unwrap();
+                assert_eq!(PointTrait::dist_sq(point, &search_point), dist_sq);
+                assert!(dist_sq <= expected[0] * bound);
+                if epsilon == 0.0 {
+                    assert_eq!(dist_sq, expected[0]);
+                }
+
+                let found = kdtree.nearest_k_approx(&search_point, 8, epsilon);
+                assert_eq!(found.len(), 8);
+                for (i, (dist_sq, point)) in found.iter().enumerate() {
+                    assert_eq!(PointTrait::dist_sq(*point, &search_point), *dist_sq);
+                    assert!(*dist_sq <= expected[i] * bound);
+                }
+            }
+        }
+        assert!(KDTree::<glam::DVec2>::default()
+            .nearest_approx(&points[0], 0.5)
+            .is_none());
+    }
+
+    #[test]
+    fn test_nearest_filtered() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..2000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let kdmap = KDMap::from_entries(points.iter().enumerate().map(|(i, p)| (*p, i)).collect());
+
+        for _ in 0..50 {
+            let search_point = glam::DVec2 {
+                x: rng.gen_range(-1.0..11.0),
+                y: rng.gen_range(-1.0..11.0),
+            };
+            let mut expected: Vec<_> = points
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| i % 7 == 3)
+                .map(|(_, p)| PointTrait::dist_sq(p, &search_point))
+                .collect();
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+            let (dist_sq, point, i) = kdmap
+                .nearest_filtered(&search_point, |_, i| i % 7 == 3)
+                .unwrap();
+            assert_eq!(dist_sq, expected[0]);
+            assert_eq!(*point, points[*i]);
+            assert_eq!(i % 7, 3);
+
+            let found = kdmap.nearest_k_filtered(&search_point, 10, |_, i| i % 7 == 3);
+            let distances: Vec<_> = found.iter().map(|(dist_sq, _, _)| *dist_sq).collect();
+            assert_eq!(distances, expected[..10]);
+            assert!(found.iter().all(|(_, _, i)| *i % 7 == 3));
+        }
+        assert!(kdmap.nearest_filtered(&points[0], |_, _| false).is_none());
+        assert!(kdmap
+            .nearest_k_filtered(&points[0], 5, |_, _| false)
+            .is_empty());
+
+        // excluding an entry by its handle still finds its duplicates
+        let mut piled = points.clone();
+        piled.extend(std::iter::repeat_n(points[0], 5000));
+        let kdtree = KDTree::from_points(piled.clone());
+        let (dist_sq, point) = kdtree.nearest_excluding(PointId(0)).unwrap();
+        assert_eq!((dist_sq, *point), (0.0, points[0]));
+        let (dist_sq, point) = kdtree.nearest_excluding(PointId(1)).unwrap();
+        let expected = points[2..]
+            .iter()
+            .chain(&points[..1])
+            .map(|p| PointTrait::dist_sq(p, &points[1]))
+            .fold(f64::INFINITY, f64::min);
+        assert_eq!(dist_sq, expected);
+        assert_eq!(PointTrait::dist_sq(point, &points[1]), expected);
+
+        let (dist_sq, point) = kdtree
+            .nearest_filtered(&points[0], |p| *p != points[0])
+            .unwrap();
+        assert!(dist_sq > 0.0);
+        assert_ne!(*point, points[0]);
+
+        let single = KDTree::from_points(vec![points[0]]);
+        assert!(single.nearest_excluding(PointId(0)).is_none());
+        assert!(single.nearest_excluding(PointId(1)).is_none());
+    }
+
+    #[test]
+    fn test_nearest_within() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..2000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0..200) as f64 / 20.0,
+                y: rng.gen_range(0..200) as f64 / 20.0,
+            })
+            .collect();
+        let kdtree = KDTree::from_points(points.clone());
+
+        for _ in 0..200 {
+            let search_point = glam::DVec2 {
+                x: rng.gen_range(-20..220) as f64 / 20.0,
+                y: rng.gen_range(-20..220) as f64 / 20.0,
+            };
+            let max_dist = rng.gen_range(0..10) as f64 / 20.0;
+            let expected = points
+                .iter()
+                .map(|p| PointTrait::dist_sq(p, &search_point))
+                .fold(f64::INFINITY, f64::min);
+
+            let found = kdtree.nearest_within(&search_point, max_dist);
+            if expected <= max_dist * max_dist {
+                let (dist_sq, point) = found.unwrap();
+                assert_eq!(dist_sq, expected);
+                assert_eq!(PointTrait::dist_sq(point, &search_point), expected);
+            } else {
+                assert!(found.is_none());
+            }
+        }
+
+        // a point exactly at max_dist qualifies
+        let search_point = glam::DVec2::new(points[0].x, points[0].y - 20.0);
+        let far = KDTree::from_points(vec![points[0]]);
+        assert!(far.nearest_within(&search_point, 19.999).is_none());
+        assert_eq!(
+            far.nearest_within(&search_point, 20.0),
+            Some((400.0, &points[0]))
+        );
+        assert!(KDTree::<glam::DVec2>::default()
+            .nearest_within(&search_point, 1.0)
+            .is_none());
+    }
+
+    #[test]
+    fn test_farthest() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..3000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let kdtree = KDTree::from_points(points.clone());
+
+        for k in [1, 5, 40] {
+            for _ in 0..30 {
+                let search_point = glam::DVec2 {
+                    x: rng.gen_range(-5.0..15.0),
+                    y: rng.gen_range(-5.0..15.0),
+                };
+                let mut expected: Vec<_> = points
+                    .iter()
+                    .map(|p| PointTrait::dist_sq(p, &search_point))
+                    .collect();
+                expected.sort_by(|a, b| b.partial_cmp(a).unwrap());
+                expected.truncate(k);
+
+                let found = kdtree.farthest_k(&search_point, k);
+                let distances: Vec<_> = found.iter().map(|(dist_sq, _)| *dist_sq).collect();
+                assert_eq!(distances, expected);
+                for (dist_sq, point) in found {
+                    assert_eq!(PointTrait::dist_sq(point, &search_point), dist_sq);
+                }
+                let (dist_sq, point) = kdtree.farthest(&search_point).unwrap();
+                assert_eq!(dist_sq, expected[0]);
+                assert_eq!(PointTrait::dist_sq(point, &search_point), dist_sq);
+            }
+        }
+        assert_eq!(
+            KDTree::from_points(points[..3].to_vec())
+                .farthest_k(&points[0], 5)
+                .len(),
+            3
+        );
+        assert!(kdtree.farthest_k(&points[0], 0).is_empty());
+        assert!(KDTree::<glam::DVec2>::default()
+            .farthest(&points[0])
+            .is_none());
+    }
+
+    #[test]
+    fn test_count_within() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        // grid coordinates put points exactly on the circles
+        let points: Vec<_> = (0..5000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0..40) as f64 / 4.0,
+                y: rng.gen_range(0..40) as f64 / 4.0,
+            })
+            .collect();
+        let mut kdtree = KDTree::from_points(points.clone());
+
+        for _ in 0..100 {
+            let search_point = glam::DVec2 {
+                x: rng.gen_range(-8..48) as f64 / 4.0,
+                y: rng.gen_range(-8..48) as f64 / 4.0,
+            };
+            let radius = rng.gen_range(0..30) as f64 / 4.0;
+            let expected = points
+                .iter()
+                .filter(|p| PointTrait::dist_sq(*p, &search_point) <= radius * radius)
+                .count();
+            assert_eq!(kdtree.count_within(&search_point, radius), expected);
+            assert_eq!(kdtree.any_within(&search_point, radius), expected > 0);
+        }
+
+        // the subtree sizes must stay exact through inserts and removals
+        for point in &points[..1000] {
+            let _ = kdtree.remove(point).unwrap();
+        }
+        for point in &points[..300] {
+            let _ = kdtree.insert(*point).unwrap();
+        }
+        let remaining: Vec<_> = points[..300].iter().chain(&points[1000..]).collect();
+        let center = glam::DVec2::new(5.0, 5.0);
+        for radius in [0.0, 1.0, 2.5, 4.0, 100.0] {
+            let expected = remaining
+                .iter()
+                .filter(|p| PointTrait::dist_sq(**p, &center) <= radius * radius)
+                .count();
+            assert_eq!(kdtree.count_within(&center, radius), expected);
+        }
+        assert_eq!(kdtree.count_within(&center, 100.0), kdtree.len());
+
+        let empty = KDTree::<glam::DVec2>::default();
+        assert_eq!(empty.count_within(&center, 100.0), 0);
+        assert!(!empty.any_within(&center, 100.0));
+    }
+
+    #[test]
+    fn test_try_closure_range_query() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use std::ops::ControlFlow;
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..2000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let kdtree = KDTree::from_points(points.clone());
+        let center = glam::DVec2::new(5.0, 5.0);
+        let in_range = points
+            .iter()
+            .filter(|p| PointTrait::dist_sq(*p, &center) <= 9.0)
+            .count();
+
+        // never breaking visits every point in range
+        let mut visited = 0;
+        let result: ControlFlow<()> = kdtree.try_closure_range_query(&center, 3.0, |_| {
+            visited += 1;
+            ControlFlow::Continue(())
+        });
+        assert_eq!(result, ControlFlow::Continue(()));
+        assert_eq!(visited, in_range);
+
+        // the traversal stops right at the break, and its value is handed back
+        let mut visited = 0;
+        let result = kdtree.try_closure_range_query(&center, 3.0, |p| {
+            visited += 1;
+            if p.x > 7.0 {
+                ControlFlow::Break(*p)
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        let ControlFlow::Break(found) = result else {
+            panic!("expected a point with x > 7 in range");
+        };
+        assert!(found.x > 7.0 && PointTrait::dist_sq(&found, &center) <= 9.0);
+        assert!(visited < in_range);
+
+        let kdmap = KDMap::from_entries(points.iter().enumerate().map(|(i, p)| (*p, i)).collect());
+        let result = kdmap.try_closure_range_query(&points[17], 0.0, |_, i| ControlFlow::Break(*i));
+        assert_eq!(result, ControlFlow::Break(17));
+    }
+
+    #[test]
+    fn test_region_query() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..5000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0..200) as f64 / 20.0,
+                y: rng.gen_range(0..200) as f64 / 20.0,
+            })
+            .collect();
+        let kdtree = KDTree::from_points(points.clone());
+        let key = |p: &glam::DVec2| (p.x, p.y);
+
+        let check = |region: &dyn Region<glam::DVec2>| {
+            let mut found = kdtree.region_query(region);
+            let mut expected: Vec<_> = points
+                .iter()
+                .filter(|p| region.contains(p))
+                .copied()
+                .collect();
+            found.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+            expected.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+            assert_eq!(found, expected);
+            expected.len()
+        };
+
+        let v = glam::DVec2::new;
+        // a concave star
+        let star = Polygon::new(
+            (0..10)
+                .map(|i| {
+                    let angle = i as f64 * std::f64::consts::PI / 5.0;
+                    let radius = if i % 2 == 0 { 4.0 } else { 1.5 };
+                    v(5.0 + radius * angle.cos(), 5.0 + radius * angle.sin())
+                })
+                .collect(),
+        )
+        .unwrap();
+        assert!(check(&star) > 0);
+        assert!(star.contains(&v(5.0, 5.0)));
+        assert!(!star.contains(&v(5.0 + 3.0 * 0.3f64.cos(), 5.0 + 3.0 * 0.3f64.sin())));
+        // a polygon smaller than any cell, and one covering the whole tree
+        let _ = check(&Polygon::new(vec![v(3.01, 3.01), v(3.09, 3.02), v(3.04, 3.08)]).unwrap());
+        assert_eq!(
+            check(&Polygon::new(vec![v(-1.0, -1.0), v(30.0, -1.0), v(-1.0, 30.0)]).unwrap()),
+            points.len()
+        );
+        assert!(Polygon::new(vec![v(0.0, 0.0), v(1.0, 1.0)]).is_err());
+
+        assert!(check(&Annulus::new(v(2.0, 7.0), 1.0, 3.0).unwrap()) > 0);
+        assert!(check(&Annulus::new(v(5.0, 5.0), 0.0, 2.5).unwrap()) > 0);
+        assert!(check(&Annulus::new(v(-5.0, 5.0), 5.5, 6.0).unwrap()) > 0);
+        assert!(Annulus::new(v(0.0, 0.0), 2.0, 1.0).is_err());
+
+        assert!(check(&HalfPlane::new(v(5.0, 5.0), v(1.0, 1.0))) > 0);
+        assert!(check(&HalfPlane::new(v(2.0, 0.0), v(-3.0, 0.5))) > 0);
+        assert_eq!(check(&HalfPlane::new(v(20.0, 0.0), v(1.0, 0.0))), 0);
+
+        let bx = HyperRectangle::new(v(2.5, 1.0), v(4.0, 8.5));
+        assert_eq!(check(&bx), kdtree.rect_query(&bx.min, &bx.max).len());
+
+        let mut count = 0;
+        kdtree.closure_region_query(&star, |_| count += 1);
+        assert_eq!(count, kdtree.region_query(&star).len());
+        assert!(KDTree::<glam::DVec2>::default()
+            .region_query(&star)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_segment_range_query() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..5000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let kdmap = KDMap::from_entries(points.iter().enumerate().map(|(i, p)| (*p, i)).collect());
+
+        // brute force distance from a point to a segment
+        let segment_dist_sq = |a: glam::DVec2, b: glam::DVec2, p: glam::DVec2| {
+            let ab = b - a;
+            let t = if ab.length_squared() > 0.0 {
+                ((p - a).dot(ab) / ab.length_squared()).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            (a + ab * t).distance_squared(p)
+        };
+        let random_point = |rng: &mut StdRng| glam::DVec2 {
+            x: rng.gen_range(-2.0..12.0),
+            y: rng.gen_range(-2.0..12.0),
+        };
+
+        for _ in 0..30 {
+            let (a, b) = (random_point(&mut rng), random_point(&mut rng));
+            let radius = rng.gen_range(0.0..1.5);
+            let mut found = Vec::new();
+            kdmap.segment_range_query(&a, &b, radius, |_, i| found.push(*i));
+            found.sort();
+            let expected: Vec<_> = (0..points.len())
+                .filter(|i| segment_dist_sq(a, b, points[*i]) <= radius * radius)
+                .collect();
+            assert_eq!(found, expected);
+
+            // a zig-zag path with overlapping capsules must still report every point once
+            let path: Vec<_> = (0..6).map(|_| random_point(&mut rng)).collect();
+            let mut found = Vec::new();
+            kdmap.polyline_range_query(&path, radius, |_, i| found.push(*i));
+            found.sort();
+            let expected: Vec<_> = (0..points.len())
+                .filter(|i| {
+                    path.windows(2)
+                        .any(|w| segment_dist_sq(w[0], w[1], points[*i]) <= radius * radius)
+                })
+                .collect();
+            assert_eq!(found, expected);
+        }
+
+        // degenerate paths
+        let kdtree = KDTree::from_points(points.clone());
+        let mut count = 0;
+        kdtree.segment_range_query(&points[0], &points[0], 0.5, |_| count += 1);
+        assert_eq!(count, kdtree.count_within(&points[0], 0.5));
+        let mut count = 0;
+        kdtree.polyline_range_query(&points[..1], 0.5, |_| count += 1);
+        assert_eq!(count, kdtree.count_within(&points[0], 0.5));
+        kdtree.polyline_range_query(&[], 0.5, |_| panic!("an empty path contains nothing"));
+    }
+
+    #[test]
+    fn test_nearest_to_segment() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..3000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let kdtree = KDTree::from_points(points.clone());
+
+        let segment_dist_sq = |a: glam::DVec2, b: glam::DVec2, p: glam::DVec2| {
+            let ab = b - a;
+            let t = if ab.length_squared() > 0.0 {
+                ((p - a).dot(ab) / ab.length_squared()).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            (a + ab * t).distance_squared(p)
+        };
+
+        for k in [1, 6, 50] {
+            for _ in 0..30 {
+                let a = glam::DVec2::new(rng.gen_range(-3.0..13.0), rng.gen_range(-3.0..13.0));
+                let b = if rng.gen_bool(0.1) {
+                    a
+                } else {
+                    glam::DVec2::new(rng.gen_range(-3.0..13.0), rng.gen_range(-3.0..13.0))
+                };
+                let mut expected: Vec<_> =
+                    points.iter().map(|p| segment_dist_sq(a, b, *p)).collect();
+                expected.sort_by(|x, y| x.partial_cmp(y).unwrap());
+                expected.truncate(k);
+
+                let found = kdtree.nearest_to_segment(&a, &b, k);
+                assert_eq!(found.len(), k);
+                for ((dist_sq, point), expected) in found.iter().zip(&expected) {
+                    assert!((dist_sq - expected).abs() < 1e-9);
+                    assert!((segment_dist_sq(a, b, **point) - dist_sq).abs() < 1e-9);
+                }
+            }
+        }
+        assert!(KDTree::<glam::DVec2>::default()
+            .nearest_to_segment(&points[0], &points[1], 3)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_nearest_in_cone() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..3000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let kdtree = KDTree::from_points(points.clone());
+
+        let brute_force = |origin: glam::DVec2, accept: &dyn Fn(glam::DVec2) -> bool| {
+            points
+                .iter()
+                .filter(|p| accept(**p - origin))
+                .map(|p| p.distance_squared(origin))
+                .min_by(|a, b| a.partial_cmp(b).unwrap())
+        };
+
+        for half_angle in [0.05, 0.5, std::f64::consts::FRAC_PI_2, 2.5] {
+            for (i, stored) in points.iter().take(50).enumerate() {
+                // some of the cones start at a stored point, which is never part of its cone
+                let origin = if i % 5 == 0 {
+                    *stored
+                } else {
+                    glam::DVec2::new(rng.gen_range(-2.0..12.0), rng.gen_range(-2.0..12.0))
+                };
+                let direction =
+                    glam::DVec2::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+                let expected = brute_force(origin, &|v| {
+                    v != glam::DVec2::ZERO && v.angle_to(direction).abs() < half_angle
+                });
+                let found = kdtree.nearest_in_cone(&origin, &direction, half_angle);
+                assert_eq!(found.map(|(dist_sq, _)| dist_sq), expected);
+                if let Some((_, point)) = found {
+                    assert!((*point - origin).angle_to(direction).abs() < half_angle);
+                    assert!(Cone::new(origin, direction, half_angle).contains(point));
+                }
+
+                let expected = brute_force(origin, &|v| v.dot(direction) > 0.0);
+                let found = kdtree.nearest_ahead(&origin, &direction);
+                assert_eq!(found.map(|(dist_sq, _)| dist_sq), expected);
+            }
+        }
+
+        // looking away from all the points
+        let origin = glam::DVec2::new(-1.0, 5.0);
+        let direction = glam::DVec2::new(-1.0, 0.0);
+        assert!(kdtree.nearest_in_cone(&origin, &direction, 1.5).is_none());
+        assert!(kdtree.nearest_ahead(&origin, &direction).is_none());
+        assert!(kdtree.nearest_ahead(&origin, &glam::DVec2::ZERO).is_none());
+        assert!(kdtree.nearest_in_cone(&origin, &-direction, 0.0).is_none());
+    }
+
+    #[test]
+    fn test_ray_cast() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng: StdRng = SeedableRng::seed_from_u64(42);
+
+        let points: Vec<_> = (0..3000)
+            .map(|_| glam::DVec2 {
+                x: rng.gen_range(0.0..10.0),
+                y: rng.gen_range(0.0..10.0),
+            })
+            .collect();
+        let kdtree = KDTree::from_points(points.clone());
+
+        for tolerance in [0.0, 0.01, 0.1, 1.0] {
+            for _ in 0..100 {
+                let origin = glam::DVec2::new(rng.gen_range(-3.0..13.0), rng.gen_range(-3.0..13.0));
+                let dir = glam::DVec2::new(rng.gen_range(-2.0..2.0), rng.gen_range(-2.0..2.0));
+                let expected = points
+                    .iter()
+                    .filter_map(|p| {
+                        let t = ((*p - origin).dot(dir) / dir.length_squared()).max(0.0);
+                        ((origin + dir * t).distance_squared(*p) <= tolerance * tolerance)
+                            .then_some(t)
+                    })
+                    .min_by(|a, b| a.partial_cmp(b).unwrap());
+
+                let found = kdtree.ray_cast(&origin, &dir, tolerance);
+                assert_eq!(found.map(|(t, _)| t), expected);
+                if let Some((t, point)) = found {
+                    assert!((origin + dir * t).distance(*point) <= tolerance + 1e-12);
+                }
+            }
+        }
+
+        // a ray through a stored point hits it even without tolerance
+        let origin = points[7] - glam::DVec2::new(3.0, 0.0);
+        let (t, point) = kdtree
+            .ray_cast(&origin, &glam::DVec2::new(1.0, 0.0), 0.0)
+            .unwrap();
+        assert_eq!(*point, points[7]);
+        assert_eq!(t, 3.0);
+        // pointing away from all the points
+        let origin = glam::DVec2::new(-1.0, 5.0);
+        assert!(kdtree
+            .ray_cast(&origin, &glam::DVec2::new(-1.0, 0.0), 0.5)
+            .is_none());
+        assert!(kdtree
+            .ray_cast(&origin, &glam::DVec2::new(1.0, 0.0), -1.0)
+            .is_none());
+    }
 }